@@ -39,11 +39,11 @@
 
 ;; Built-ins are instructions that just generate ir
 (defconst loisp-builtins
-  '("print" "+" "-" "*" "/" "%" "=" "!=" "<" ">" "<=" ">=" "load64" "store64" "load32" "store32" "load16" "store16" "load8" "store8" "<<" ">>" "&" "|" "!" "castint" "castptr" "argc" "argv" "envp"))
+  '("print" "eprint" "printhex" "printbin" "+" "-" "*" "/" "%" "=" "!=" "<" ">" "<=" ">=" "load64" "store64" "load32" "store32" "load16" "store16" "load8" "store8" "load32s" "load16s" "load8s" "load64off" "store64off" "load32off" "store32off" "load16off" "store16off" "load8off" "store8off" "load" "store" "<<" ">>" "&" "|" "!" "lnot" "castint" "castptr" "argc" "argv" "argat" "assert" "envp" "fopen" "fread" "fwrite" "fclose" "strcat" "strbyte" "aget" "aset" "field"))
 
 ;; Keywords are instructions that do something more than just generating ir
 (defconst loisp-keywords
-  '("syscall" "setvar" "getvar" "chvar" "while" "if" "block" "ptrto" "alloc" "getmem" "macro" "expand" "pop" "include" "defun" "call" "increment" "reset" "typeof"))
+  '("syscall" "setvar" "getvar" "chvar" "while" "if" "switch" "case" "default" "block" "ptrto" "alloc" "allocstr" "array" "struct" "getmem" "macro" "expand" "pop" "include" "defun" "call" "return" "returns" "increment" "decrement" "+=" "-=" "*=" "/=" "reset" "typeof" "pragma"))
 
 (defun loisp-wrap-word-rx (s)
   (concat "\\<" s "\\>"))
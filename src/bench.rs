@@ -0,0 +1,128 @@
+use super::config::*;
+use super::emulator::*;
+use super::instructions::*;
+use super::ir::*;
+use super::parser::*;
+use super::print_info;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::process;
+use std::time::{Duration, Instant};
+
+pub static DEFAULT_BENCH_COUNT: usize = 10;
+
+pub fn time<T>(durations: &mut Vec<Duration>, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    durations.push(start.elapsed());
+    result
+}
+
+// the time each phase of a single compilation took, for `--timings`
+pub struct Timings {
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings { phases: vec![] }
+    }
+
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.phases.push((phase, start.elapsed()));
+        result
+    }
+
+    pub fn print(&self) {
+        for (phase, duration) in &self.phases {
+            print_info!("TIMINGS", "{:<11} {:?}", format!("{}:", phase), duration);
+        }
+        let total: Duration = self.phases.iter().map(|(_, d)| *d).sum();
+        print_info!("TIMINGS", "{:<11} {:?}", "total:", total);
+    }
+}
+
+pub fn print_durations(phase: &str, durations: &mut [Duration]) {
+    durations.sort();
+    print_info!(
+        "BENCH",
+        "{:<8} min {:?}, median {:?}, max {:?}",
+        format!("{}:", phase),
+        durations[0],
+        durations[durations.len() / 2],
+        durations[durations.len() - 1]
+    );
+}
+
+pub fn bench_file(config: Config, count: usize) -> Result<(), LoispError> {
+    let source = read_source_file(config.input.as_str())?;
+    let mut quiet = config.clone();
+    quiet.silent = true;
+    // only the time it takes to generate the assembly matters, so
+    // it goes to a temporary file instead of next to the input
+    let output_assembly = env::temp_dir()
+        .join(format!("loisp-bench-{}.asm", process::id()))
+        .display()
+        .to_string();
+
+    let mut parse = vec![];
+    let mut lower = vec![];
+    let mut codegen = vec![];
+    let mut run = vec![];
+    let mut output = None;
+
+    for _ in 0..count {
+        let instructions = time(&mut parse, || {
+            try_parse(source.as_str(), config.input.clone())
+        })?;
+
+        let mut context = LoispContext::new();
+        if config.no_std_search {
+            context.search_paths.clear();
+        }
+        context.allow_redefine = config.allow_redefine;
+        let ir = time(&mut lower, || {
+            lower_instructions(instructions, &mut context)
+        })?;
+
+        time(&mut codegen, || {
+            ir.to_fasm_linux_x86_64_assembly(
+                output_assembly.clone(),
+                quiet.clone(),
+                &mut IrContext::new(),
+            )
+        })?;
+
+        if config.emulate {
+            // the output is the same every time, so it's only shown once
+            let mut emulator = emulator_for_config(&config);
+            let (out, err) = emulator.capture_output();
+            time(&mut run, || emulate_ir(&ir, &mut emulator))?;
+            if output.is_none() {
+                output = Some((out.bytes(), err.bytes()));
+            }
+        }
+    }
+
+    let _ = fs::remove_file(&output_assembly);
+
+    if let Some((out, err)) = output {
+        io::stdout().write_all(&out)?;
+        io::stderr().write_all(&err)?;
+    }
+
+    print_info!("BENCH", "`{}`, {} runs", config.input, count);
+    print_durations("parse", &mut parse);
+    print_durations("lower", &mut lower);
+    print_durations("codegen", &mut codegen);
+    if config.emulate {
+        print_durations("run", &mut run);
+    }
+
+    Ok(())
+}
@@ -1,16 +1,43 @@
 use super::config::*;
 
 use std::env;
+use std::fs;
 use std::io;
-use std::process::{Command, Stdio};
+use std::io::IsTerminal;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
 
 #[macro_export]
 macro_rules! print_info {
     ($p:literal,$($arg:tt)*) => {{
-        print!("{}", format!("[{}] {}\n", $p, format!($($arg)*)));
+        print!("{}", format!("[{}] {}\n", $crate::common::colored_tag($p), format!($($arg)*)));
     }};
 }
 
+pub fn is_tty<T: IsTerminal>(stream: T) -> bool {
+    stream.is_terminal()
+}
+
+pub fn colored_tag(tag: &str) -> String {
+    // escape codes would end up as garbage in files and
+    // pipes, so only color the output of a terminal
+    color_tag(tag, is_tty(io::stdout()))
+}
+
+pub fn color_tag(tag: &str, color: bool) -> String {
+    if !color {
+        return tag.to_string();
+    }
+
+    match tag {
+        "ERROR" => format!("\x1b[31m{}\x1b[0m", tag),
+        "WARN" => format!("\x1b[33m{}\x1b[0m", tag),
+        _ => tag.to_string(),
+    }
+}
+
 pub fn file_name_without_extension(f: String) -> String {
     // if the file name begins with dots, store them in
     // the variable `dots`
@@ -38,6 +65,40 @@ pub fn file_name_without_extension(f: String) -> String {
     return returnn.trim().to_string();
 }
 
+// removes the `.`, `..` and doubled slashes of a path without touching
+// the filesystem, so the path can still be shown as the user wrote it
+pub fn normalize_path(f: &str) -> String {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(f).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        format!("{}", normalized.display())
+    }
+}
+
+pub fn canonical_path(f: &str) -> String {
+    // fall back to the path as given when it can't be resolved
+    // (e.g. `<stdin>` in the REPL)
+    match fs::canonicalize(f) {
+        Ok(p) => format!("{}", p.display()),
+        Err(_) => f.to_string(),
+    }
+}
+
 pub fn escape_string(string: String) -> String {
     return string
         .replace("\\n", "\n")
@@ -48,27 +109,98 @@ pub fn escape_string(string: String) -> String {
         .replace("\\\\", "\\");
 }
 
-pub fn run_command_with_info(cmd: String, config: Config) -> io::Result<()> {
-    if !config.silent {
-        print_info!("CMD", "{}", cmd)
+fn status_with_config(mut cmd: Command, config: Config) -> io::Result<ExitStatus> {
+    let cwd = format!("{}", env::current_dir()?.display());
+    cmd.current_dir(cwd.as_str());
+
+    // interactive programs read from the terminal
+    cmd.stdin(Stdio::inherit());
+
+    if !config.piped {
+        cmd.stdout(Stdio::inherit());
+    } else {
+        cmd.stdout(Stdio::null());
     }
+    cmd.stderr(Stdio::inherit());
 
-    let mut shell_cmd = Command::new("sh");
-    shell_cmd.args(["-c", cmd.as_str()]);
+    cmd.status()
+}
 
-    let cwd = format!("{}", env::current_dir()?.display());
-    shell_cmd.current_dir(cwd.as_str());
+pub fn shell_quote(arg: &str) -> String {
+    // only quote the arguments that need it, so that the common
+    // case of the logged commands stays readable
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
 
-    if !config.piped {
-        shell_cmd.stdout(Stdio::inherit());
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+pub fn executable_path(f: &str) -> String {
+    // a name without a slash would be searched in $PATH
+    if f.contains('/') {
+        f.to_string()
     } else {
-        shell_cmd.stdout(Stdio::null());
+        format!("./{}", f)
+    }
+}
+
+pub fn run_program_with_status(args: &[String], config: Config) -> io::Result<ExitStatus> {
+    if !config.silent {
+        let quoted: Vec<String> = args.iter().map(|a| shell_quote(a)).collect();
+        print_info!("CMD", "{}", quoted.join(" "))
     }
-    shell_cmd.stderr(Stdio::inherit());
 
-    let status = shell_cmd
-        .status()
-        .expect(format!("Command {} failed to execute", cmd).as_str());
+    // the arguments are given to the program as they are, without
+    // going through a shell, so paths with spaces or shell
+    // metacharacters can't break or inject into the command
+    let mut cmd = Command::new(args[0].as_str());
+    cmd.args(&args[1..]);
+
+    status_with_config(cmd, config)
+}
+
+#[cfg(unix)]
+pub fn exit_status_as_code(status: ExitStatus) -> i32 {
+    // follow the shell convention of reporting a
+    // process killed by a signal as 128 + signal
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn exit_status_as_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
+
+#[cfg(unix)]
+pub fn finish_executable(tmp: &str, out: &str) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(tmp)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    fs::set_permissions(tmp, permissions)?;
+    fs::rename(tmp, out)
+}
+
+#[cfg(not(unix))]
+pub fn finish_executable(_tmp: &str, _out: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "executables can only be generated on Unix hosts",
+    ))
+}
+
+pub fn run_program_with_info(args: &[String], config: Config) -> io::Result<()> {
+    let status = run_program_with_status(args, config).map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!("Command `{}` failed to execute: {}", args[0], error),
+        )
+    })?;
 
     match status.code() {
         Some(code) => {
@@ -85,3 +217,37 @@ pub fn run_command_with_info(cmd: String, config: Config) -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_program_with_info_returns_the_error_of_a_missing_command() {
+        let mut config = Config::new();
+        config.silent = true;
+        let args = ["./this-command-does-not-exist".to_string()];
+
+        let error = run_program_with_info(&args, config).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error
+            .to_string()
+            .contains("`./this-command-does-not-exist`"));
+    }
+
+    #[test]
+    fn shell_quote_only_quotes_arguments_that_need_it() {
+        assert_eq!(shell_quote("tests/out.asm"), "tests/out.asm");
+        assert_eq!(shell_quote("with space"), "'with space'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn color_tag_is_plain_when_the_output_is_redirected() {
+        assert_eq!(color_tag("ERROR", false), "ERROR");
+        assert_eq!(color_tag("WARN", false), "WARN");
+        assert_eq!(color_tag("ERROR", true), "\x1b[31mERROR\x1b[0m");
+        assert_eq!(color_tag("INFO", true), "INFO");
+    }
+}
@@ -13,12 +13,48 @@ impl ConfigRun {
     }
 }
 
+pub static DEFAULT_BUFFER_CAPACITY: usize = 640000; // should be enough for everyone
+
+#[derive(Clone)]
+pub struct ConfigEmulator {
+    pub string_capacity: usize,
+    pub variable_capacity: usize,
+    pub memory_capacity: usize,
+    pub args_capacity: usize,
+    pub vars_capacity: usize,
+    pub strict_stores: bool,
+}
+
+impl ConfigEmulator {
+    pub fn new() -> ConfigEmulator {
+        ConfigEmulator {
+            string_capacity: DEFAULT_BUFFER_CAPACITY,
+            variable_capacity: DEFAULT_BUFFER_CAPACITY,
+            memory_capacity: DEFAULT_BUFFER_CAPACITY,
+            args_capacity: DEFAULT_BUFFER_CAPACITY,
+            vars_capacity: DEFAULT_BUFFER_CAPACITY,
+            strict_stores: false,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub silent: bool,
     pub run: ConfigRun,
+    pub emulator: ConfigEmulator,
     pub piped: bool,
     pub emulate: bool,
+    pub lex: bool,
+    pub emit_tokens_json: bool,
+    pub stats: bool,
+    pub count: bool,
+    pub profile: bool,
+    pub dump_context: bool,
+    pub no_std_search: bool,
+    pub timings: bool,
+    pub assert_ir: bool,
+    pub allow_redefine: bool,
     pub input: String,
     pub output: Option<String>,
 }
@@ -28,10 +64,21 @@ impl Config {
         Config {
             silent: false,
             run: ConfigRun::new(),
+            emulator: ConfigEmulator::new(),
             piped: false,
             input: String::new(),
             output: None,
             emulate: false,
+            lex: false,
+            emit_tokens_json: false,
+            stats: false,
+            count: false,
+            profile: false,
+            dump_context: false,
+            no_std_search: false,
+            timings: false,
+            assert_ir: false,
+            allow_redefine: false,
         }
     }
 }
@@ -1,67 +1,156 @@
+use super::bench::*;
 use super::common::*;
 use super::config::*;
+use super::instructions::*;
 use super::ir::*;
+use super::types::*;
+use super::print_info;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env::vars;
+use std::error;
+use std::fmt;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::*;
+use std::ops::{Index, IndexMut};
 use std::process::*;
+use std::rc::Rc;
 
+// an in-memory fd, whose bytes can still be read after the emulation
+#[derive(Clone)]
+pub struct SharedBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> SharedBuffer {
+        SharedBuffer(Rc::new(RefCell::new(vec![])))
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// an fd of the emulated program, either side of which can be missing
 pub struct Stream {
-    pub stdout: Option<Stdout>,
-    pub stderr: Option<Stderr>,
-    pub stdin: Option<Stdin>,
-    pub file: Option<File>,
+    pub reader: Option<Box<dyn Read>>,
+    pub writer: Option<Box<dyn Write>>,
 }
 
 impl Stream {
     pub fn new() -> Stream {
         Stream {
-            stdout: None,
-            stderr: None,
-            stdin: None,
-            file: None,
+            reader: None,
+            writer: None,
         }
     }
 
-    pub fn write(&self, s: String) -> Result<()> {
-        if !self.stdout.is_none() {
-            write!(self.stdout.as_ref().unwrap(), "{}", s)?;
-            self.stdout.as_ref().unwrap().flush()?;
-        } else if !self.stderr.is_none() {
-            write!(self.stderr.as_ref().unwrap(), "{}", s)?;
-            self.stderr.as_ref().unwrap().flush()?;
-        } else if !self.file.is_none() {
-            write!(self.file.as_ref().unwrap(), "{}", s)?;
-            self.file.as_ref().unwrap().flush()?;
-        } else if !self.stdin.is_none() {
-            return Err(Error::new(ErrorKind::Other, "EBADFD"));
+    pub fn with_reader(reader: impl Read + 'static) -> Stream {
+        let mut stream = Stream::new();
+        stream.reader = Some(Box::new(reader));
+        stream
+    }
+
+    pub fn with_writer(writer: impl Write + 'static) -> Stream {
+        let mut stream = Stream::new();
+        stream.writer = Some(Box::new(writer));
+        stream
+    }
+
+    // both sides share the offset of the file
+    pub fn from_file(file: File) -> Result<Stream> {
+        let mut stream = Stream::with_reader(file.try_clone()?);
+        stream.writer = Some(Box::new(file));
+        Ok(stream)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_all(bytes)?;
+            writer.flush()?;
+            Ok(())
+        } else {
+            Err(Error::other("EBADFD"))
         }
+    }
 
-        Ok(())
+    pub fn read(&mut self, count: usize) -> Result<Vec<u8>> {
+        let mut bytes: Vec<u8> = vec![0; count];
+
+        if let Some(reader) = self.reader.as_mut() {
+            let n = reader.read(&mut bytes)?;
+            bytes.truncate(n);
+            Ok(bytes)
+        } else {
+            Err(Error::other("EBADFD"))
+        }
     }
+}
 
-    pub fn read(&self) -> Result<String> {
-        let mut result = String::new();
-
-        if !self.stdout.is_none() {
-            stdin().read_line(&mut result)?;
-        } else if !self.stderr.is_none() {
-            stdin().read_line(&mut result)?;
-        } else if !self.stdin.is_none() {
-            stdin().read_line(&mut result)?;
-        } else if !self.file.is_none() {
-            let mut bytes: Vec<u8> = vec![];
-            self.file.as_ref().unwrap().read_to_end(&mut bytes)?;
-            result = match String::from_utf8(bytes.to_vec()) {
-                Ok(s) => s,
-                Err(_) => String::new(),
-            }
+pub static PAGE_SIZE: usize = 4096;
+
+// the memory of the emulated program, split into pages that are only
+// allocated when something is written to them, so the untouched parts
+// of the buffers read as zeros without taking any space
+pub struct Memory {
+    pages: Vec<Option<Box<[u8]>>>,
+    len: usize,
+}
+
+impl Memory {
+    pub fn new(len: usize) -> Memory {
+        Memory {
+            pages: (0..len.div_ceil(PAGE_SIZE)).map(|_| None).collect(),
+            len,
         }
+    }
+
+    // how many bytes can be accessed
+    pub fn capacity(&self) -> usize {
+        self.len
+    }
 
-        Ok(result)
+    // how many bytes are really allocated
+    pub fn allocated(&self) -> usize {
+        self.pages.iter().filter(|p| p.is_some()).count() * PAGE_SIZE
+    }
+
+    fn check_bounds(&self, index: usize) {
+        if index >= self.len {
+            panic!("out of bounds memory access at address {}", index);
+        }
+    }
+}
+
+impl Index<usize> for Memory {
+    type Output = u8;
+
+    fn index(&self, index: usize) -> &u8 {
+        self.check_bounds(index);
+        match &self.pages[index / PAGE_SIZE] {
+            Some(page) => &page[index % PAGE_SIZE],
+            None => &0,
+        }
+    }
+}
+
+impl IndexMut<usize> for Memory {
+    fn index_mut(&mut self, index: usize) -> &mut u8 {
+        self.check_bounds(index);
+        let page = self.pages[index / PAGE_SIZE].get_or_insert_with(|| vec![0; PAGE_SIZE].into());
+        &mut page[index % PAGE_SIZE]
     }
 }
 
@@ -80,86 +169,219 @@ pub struct Emulator {
 
     pub ret_stack: Vec<usize>,
 
-    pub fds: HashMap<usize, Stream>,
+    // several fds can share a stream, after `dup` or `dup2`
+    pub fds: HashMap<usize, Rc<RefCell<Stream>>>,
 
-    pub memory: Vec<u8>,
-}
+    pub memory: Memory,
 
-static NULL_PTR_PADDING: usize = 1;
+    pub buffers: ConfigEmulator,
 
-static STRING_BUFFER_CAPACITY: usize = 640000; // should be enough for everyone
-static STRING_BUFFER_START: usize = NULL_PTR_PADDING;
+    pub track_types: bool,
+    pub types: Vec<LoispDatatype>,
 
-static VARIABLE_BUFFER_CAPACITY: usize = 640000; // should be enough for everyone
-static VARIABLE_BUFFER_START: usize = STRING_BUFFER_START + STRING_BUFFER_CAPACITY;
+    // set when the program calls `exit`, which stops the emulation
+    pub exit_code: Option<i32>,
+
+    // how many instructions were executed, for `--count`
+    pub steps: usize,
+    // how many times each instruction was executed, for `--profile`
+    pub profile: Option<Vec<u64>>,
+}
 
-static MEMORY_BUFFER_CAPACITY: usize = 640000; // should be enough for everyone
-static MEMORY_BUFFER_START: usize = VARIABLE_BUFFER_START + VARIABLE_BUFFER_CAPACITY;
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum EmulatorError {
+    StringBufferExhausted(usize),
+    VariableBufferExhausted(usize, usize),
+    MemoryBufferExhausted(usize, usize),
+    InvalidJumpTarget(usize, IrInstructionKind, i64, usize),
+}
 
-static ARGS_BUFFER_CAPACITY: usize = 640000; // should be enough for everyone
-static ARGS_BUFFER_START: usize = MEMORY_BUFFER_START + MEMORY_BUFFER_CAPACITY;
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        match self {
+            Self::StringBufferExhausted(capacity) => write!(
+                f,
+                "ERROR: String buffer exhausted: the string literals don't fit in {} bytes (see `--string-size`)",
+                capacity
+            )?,
+            Self::VariableBufferExhausted(needed, capacity) => write!(
+                f,
+                "ERROR: Variable buffer exhausted: the program allocates {} bytes but the capacity is {} bytes (see `--var-size`)",
+                needed, capacity
+            )?,
+            Self::MemoryBufferExhausted(needed, capacity) => write!(
+                f,
+                "ERROR: Memory buffer exhausted: the program allocates {} bytes but the capacity is {} bytes (see `--mem-size`)",
+                needed, capacity
+            )?,
+            Self::InvalidJumpTarget(ip, kind, target, count) => write!(
+                f,
+                "ERROR: Malformed IR: the {:?} at instruction {} goes to {}, but the program has {} instructions",
+                kind, ip, target, count
+            )?,
+        }
+        Ok(())
+    }
+}
 
-static VARS_BUFFER_CAPACITY: usize = 640000; // should be enough for everyone
-static VARS_BUFFER_START: usize = ARGS_BUFFER_START + ARGS_BUFFER_CAPACITY;
+impl error::Error for EmulatorError {}
 
-static X86_64_MEMORY_CAPACITY: usize = NULL_PTR_PADDING
-    + STRING_BUFFER_CAPACITY
-    + VARIABLE_BUFFER_CAPACITY
-    + MEMORY_BUFFER_CAPACITY
-    + ARGS_BUFFER_CAPACITY
-    + VARS_BUFFER_CAPACITY;
+static NULL_PTR_PADDING: usize = 1;
 
 impl Emulator {
     pub fn new() -> Emulator {
+        Emulator::with_buffers(ConfigEmulator::new())
+    }
+
+    pub fn with_buffers(buffers: ConfigEmulator) -> Emulator {
         let mut ctx = Emulator {
             args: vec![],
             stack: vec![],
             ip: 0,
 
-            string_size: STRING_BUFFER_START,
+            string_size: 0,
 
             variables: HashMap::new(),
-            variables_size: VARIABLE_BUFFER_START,
+            variables_size: 0,
 
             memories: HashMap::new(),
-            memories_size: MEMORY_BUFFER_START,
+            memories_size: 0,
 
             ret_stack: vec![],
 
             fds: HashMap::new(),
 
-            memory: vec![0; X86_64_MEMORY_CAPACITY],
+            memory: Memory::new(0),
+
+            buffers,
+
+            track_types: false,
+            types: vec![],
+
+            exit_code: None,
+
+            steps: 0,
+            profile: None,
         };
 
-        let mut fd0 = Stream::new();
-        let mut fd1 = Stream::new();
-        let mut fd2 = Stream::new();
-        fd1.stdout = Some(stdout());
-        fd2.stderr = Some(stderr());
-        fd0.stdin = Some(stdin());
+        ctx.string_size = ctx.string_buffer_start();
+        ctx.variables_size = ctx.variable_buffer_start();
+        ctx.memories_size = ctx.memory_buffer_start();
+        ctx.memory = Memory::new(ctx.x86_64_memory_capacity());
 
-        ctx.fds.insert(0, fd0);
-        ctx.fds.insert(1, fd1);
-        ctx.fds.insert(2, fd2);
+        // like on a terminal, reading fds 1 and 2 reads stdin
+        let fd0 = Stream::with_reader(stdin());
+        let mut fd1 = Stream::with_writer(stdout());
+        let mut fd2 = Stream::with_writer(stderr());
+        fd1.reader = Some(Box::new(stdin()));
+        fd2.reader = Some(Box::new(stdin()));
+
+        ctx.fds.insert(0, Rc::new(RefCell::new(fd0)));
+        ctx.fds.insert(1, Rc::new(RefCell::new(fd1)));
+        ctx.fds.insert(2, Rc::new(RefCell::new(fd2)));
 
         ctx
     }
 
-    pub fn init(&mut self, ir: IrProgram) {
+    // makes the program write its stdout and stderr into
+    // the returned buffers, instead of those of the process
+    pub fn capture_output(&mut self) -> (SharedBuffer, SharedBuffer) {
+        let out = SharedBuffer::new();
+        let err = SharedBuffer::new();
+
+        self.set_writer(1, out.clone());
+        self.set_writer(2, err.clone());
+
+        (out, err)
+    }
+
+    // replaces where the writes to `fd` go, keeping how it is read
+    pub fn set_writer(&mut self, fd: usize, writer: impl Write + 'static) {
+        self.fds
+            .entry(fd)
+            .or_insert_with(|| Rc::new(RefCell::new(Stream::new())))
+            .borrow_mut()
+            .writer = Some(Box::new(writer));
+    }
+
+    // like the kernel, a new fd is the lowest one that isn't open
+    pub fn insert_fd(&mut self, stream: Rc<RefCell<Stream>>) -> usize {
+        let mut fd = 0;
+        while self.fds.contains_key(&fd) {
+            fd += 1;
+        }
+        self.fds.insert(fd, stream);
+        fd
+    }
+
+    pub fn string_buffer_start(&self) -> usize {
+        NULL_PTR_PADDING
+    }
+
+    pub fn variable_buffer_start(&self) -> usize {
+        self.string_buffer_start() + self.buffers.string_capacity
+    }
+
+    pub fn memory_buffer_start(&self) -> usize {
+        self.variable_buffer_start() + self.buffers.variable_capacity
+    }
+
+    pub fn args_buffer_start(&self) -> usize {
+        self.memory_buffer_start() + self.buffers.memory_capacity
+    }
+
+    pub fn vars_buffer_start(&self) -> usize {
+        self.args_buffer_start() + self.buffers.args_capacity
+    }
+
+    pub fn x86_64_memory_capacity(&self) -> usize {
+        self.vars_buffer_start() + self.buffers.vars_capacity
+    }
+
+    pub fn init(&mut self, ir: IrProgram) -> std::result::Result<(), EmulatorError> {
         for i in ir.instructions {
             match i.kind {
                 IrInstructionKind::AllocVariable => {
                     self.variables
                         .insert(self.variables.len(), self.variables_size);
                     self.variables_size += i.operand.integer as usize;
+
+                    if self.variables_size > self.memory_buffer_start() {
+                        return Err(EmulatorError::VariableBufferExhausted(
+                            self.variables_size - self.variable_buffer_start(),
+                            self.buffers.variable_capacity,
+                        ));
+                    }
                 }
                 IrInstructionKind::AllocMemory => {
-                    self.memories
-                        .insert(self.memories.len(), self.memories_size);
+                    let start = self.memories_size;
+                    self.memories.insert(self.memories.len(), start);
                     self.memories_size += i.operand.integer as usize;
+
+                    if self.memories_size > self.args_buffer_start() {
+                        return Err(EmulatorError::MemoryBufferExhausted(
+                            self.memories_size - self.memory_buffer_start(),
+                            self.buffers.memory_capacity,
+                        ));
+                    }
+
+                    // the bytes of `allocstr`, the null terminator is already there
+                    let data = escape_string(i.operand.string);
+                    for (i, b) in data.bytes().enumerate() {
+                        self.memory[start + i] = b;
+                    }
                 }
                 IrInstructionKind::PushString => {
                     let string = escape_string(i.operand.string);
+
+                    // the string and its null terminator
+                    if self.string_size + string.len() + 1 > self.variable_buffer_start() {
+                        return Err(EmulatorError::StringBufferExhausted(
+                            self.buffers.string_capacity,
+                        ));
+                    }
+
                     for c in string.as_bytes() {
                         self.memory[self.string_size] = *c;
                         self.string_size += 1;
@@ -170,55 +392,82 @@ impl Emulator {
                 _ => {}
             }
         }
-    }
 
-    pub fn find_string_in_memory(&self, string: String) -> Option<usize> {
-        let mut pos_search = 0;
-        let mut pos_text = 0;
-        let len_search = string.len();
-        let len_text = STRING_BUFFER_CAPACITY;
+        Ok(())
+    }
 
-        if len_search == 0 {
-            return Some(0);
+    pub fn track_type(&mut self, op: &IrInstruction) {
+        if !self.track_types {
+            return;
         }
 
-        while pos_text < len_text - len_search {
-            if self.memory[pos_text] == string.as_bytes()[pos_search] {
-                pos_search += 1;
-                if pos_search == len_search {
-                    return Some(pos_text - (len_search - 1));
-                }
-            } else {
-                pos_text -= pos_search;
-                pos_search = 0;
-            }
+        // every instruction that returns something leaves exactly
+        // one value of its type on top of the stack
+        let typ = op.get_loisp_datatype();
+        if typ != LoispDatatype::Nothing && !self.stack.is_empty() {
+            self.types.truncate(self.stack.len() - 1);
+            self.types
+                .resize(self.stack.len() - 1, LoispDatatype::Integer);
+            self.types.push(typ);
+        } else {
+            self.types.resize(self.stack.len(), LoispDatatype::Integer);
+        }
+    }
 
-            pos_text += 1;
+    // with `--strict-stores`, storing a value that doesn't fit in the
+    // width, as signed or unsigned, stops the program instead of truncating it
+    pub fn check_store_fits(&self, value: i64, bytes: u32) {
+        let bits = bytes * 8;
+        if self.buffers.strict_stores && (value < -(1 << (bits - 1)) || value >= 1 << bits) {
+            eprintln!(
+                "ERROR: Value {} doesn't fit in {} bits (see `--strict-stores`)",
+                value, bits
+            );
+            exit(1);
         }
+    }
 
-        return None;
+    // the string is looked for with its null terminator, so that it isn't
+    // found at the start of a longer one, and anywhere in the string buffer,
+    // up to its last byte
+    pub fn find_string_in_memory(&self, string: String) -> Option<usize> {
+        let mut bytes = string.into_bytes();
+        bytes.push(0);
+
+        let last = self.variable_buffer_start().checked_sub(bytes.len())?;
+        (self.string_buffer_start()..=last).find(|&addr| {
+            bytes
+                .iter()
+                .enumerate()
+                .all(|(i, b)| self.memory[addr + i] == *b)
+        })
     }
 }
 
 pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
+    let args_buffer_start = emulator.args_buffer_start();
+    let vars_buffer_start = emulator.vars_buffer_start();
+
     let argv;
     {
         let mut ptrs: Vec<u64> = vec![];
         let mut i: usize = 0;
         for arg in &emulator.args {
-            ptrs.push((ARGS_BUFFER_START + i) as u64);
+            ptrs.push((args_buffer_start + i) as u64);
             for c in arg.as_bytes() {
-                emulator.memory[ARGS_BUFFER_START + i] = *c as u8;
+                emulator.memory[args_buffer_start + i] = *c as u8;
                 i += 1;
             }
-            emulator.memory[ARGS_BUFFER_START + i] = 0;
+            emulator.memory[args_buffer_start + i] = 0;
             i += 1;
         }
+        // argv is terminated by a null pointer, just like on Linux
+        ptrs.push(0);
 
-        argv = (ARGS_BUFFER_START + i) as i64;
+        argv = (args_buffer_start + i) as i64;
         for ptr in ptrs {
             for b in ptr.to_le_bytes() {
-                emulator.memory[ARGS_BUFFER_START + i] = b;
+                emulator.memory[args_buffer_start + i] = b;
                 i += 1;
             }
         }
@@ -231,31 +480,63 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
         for (key, value) in vars() {
             let env = format!("{}={}", key, value);
 
-            ptrs.push((VARS_BUFFER_START + i) as u64);
+            ptrs.push((vars_buffer_start + i) as u64);
             for b in env.clone().as_bytes() {
-                emulator.memory[VARS_BUFFER_START + i] = *b;
+                emulator.memory[vars_buffer_start + i] = *b;
                 i += 1;
             }
-            emulator.memory[VARS_BUFFER_START + i] = 0;
+            emulator.memory[vars_buffer_start + i] = 0;
             i += 1;
         }
         ptrs.push(0);
 
-        envp = (VARS_BUFFER_START + i) as i64;
+        envp = (vars_buffer_start + i) as i64;
         for p in ptrs {
             for b in p.to_le_bytes() {
-                emulator.memory[VARS_BUFFER_START + i] = b;
+                emulator.memory[vars_buffer_start + i] = b;
                 i += 1;
             }
         }
     }
 
+    let mut previous: Option<IrInstruction> = None;
     while emulator.ip < ir.instructions.len() {
+        if let Some(p) = previous.take() {
+            emulator.track_type(&p);
+        }
+
         let op = ir.instructions[emulator.ip].clone();
+        previous = Some(op.clone());
+        emulator.steps += 1;
+        if let Some(count) = emulator
+            .profile
+            .as_mut()
+            .and_then(|p| p.get_mut(emulator.ip))
+        {
+            *count += 1;
+        }
         match op.kind {
-            IrInstructionKind::Print => {
+            IrInstructionKind::Print
+            | IrInstructionKind::EPrint
+            | IrInstructionKind::PrintHex
+            | IrInstructionKind::PrintBin => {
                 if let Some(a) = emulator.stack.pop() {
-                    println!("{}", a);
+                    // through the fds, so the output can be captured
+                    let fd = if op.kind == IrInstructionKind::EPrint {
+                        2
+                    } else {
+                        1
+                    };
+                    // like the compiled `print_bits`, negative numbers
+                    // are printed as their unsigned 64 bit counterpart
+                    let text = match op.kind {
+                        IrInstructionKind::PrintHex => format!("{:x}\n", a as u64),
+                        IrInstructionKind::PrintBin => format!("{:b}\n", a as u64),
+                        _ => format!("{}\n", a),
+                    };
+                    if let Some(stream) = emulator.fds.get(&fd) {
+                        let _ = stream.borrow_mut().write(text.as_bytes());
+                    }
                     emulator.ip += 1;
                 }
             }
@@ -279,7 +560,8 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                     panic!("stack underflow")
                 }
 
-                emulator.stack.push(a + b);
+                // like `add`, it wraps around instead of overflowing
+                emulator.stack.push(a.wrapping_add(b));
                 emulator.ip += 1;
             }
             IrInstructionKind::Minus => {
@@ -298,7 +580,8 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                     panic!("stack underflow")
                 }
 
-                emulator.stack.push(a - b);
+                // like `sub`, it wraps around instead of overflowing
+                emulator.stack.push(a.wrapping_sub(b));
                 emulator.ip += 1;
             }
             IrInstructionKind::Multiplication => {
@@ -317,7 +600,8 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                     panic!("stack underflow")
                 }
 
-                emulator.stack.push(a * b);
+                // like `imul`, only the low 64 bits of the result are kept
+                emulator.stack.push(a.wrapping_mul(b));
                 emulator.ip += 1;
             }
             IrInstructionKind::Division => {
@@ -359,6 +643,10 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                 emulator.ip += 1;
             }
             IrInstructionKind::Syscall => {
+                let base = emulator
+                    .stack
+                    .len()
+                    .saturating_sub(op.operand.integer as usize);
                 let syscall_number;
                 if let Some(id) = emulator.stack.pop() {
                     syscall_number = id;
@@ -393,9 +681,13 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
 
                         let buffer;
                         if let Some(stream) = emulator.fds.get(&(fd as usize)) {
-                            buffer = match stream.read() {
-                                Ok(s) => s,
-                                Err(_) => String::new(),
+                            buffer = match stream.borrow_mut().read(count as usize) {
+                                Ok(b) => b,
+                                Err(_) => {
+                                    emulator.stack.push(-5);
+                                    emulator.ip += 1;
+                                    continue;
+                                }
                             };
                         } else {
                             emulator.stack.push(-77);
@@ -403,16 +695,12 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                             continue;
                         }
 
-                        for i in 0..count {
-                            if i >= (buffer.as_bytes().len() as i64) {
-                                emulator.memory[buf as usize] = 0;
-                            } else {
-                                emulator.memory[buf as usize] = buffer.as_bytes()[i as usize];
-                            }
+                        for b in &buffer {
+                            emulator.memory[buf as usize] = *b;
                             buf += 1;
                         }
 
-                        emulator.stack.push(count);
+                        emulator.stack.push(buffer.len() as i64);
                     }
                     1 => {
                         // SYS_write
@@ -446,17 +734,8 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                             i += 1;
                         }
 
-                        let mut buffer = String::new();
-                        if let Ok(string) = String::from_utf8(bytes.clone()) {
-                            buffer = string;
-                        } else {
-                            for b in bytes {
-                                buffer.push(b as char);
-                            }
-                        };
-
                         if let Some(stream) = emulator.fds.get(&(fd as usize)) {
-                            if let Err(_) = stream.write(buffer) {
+                            if stream.borrow_mut().write(&bytes).is_err() {
                                 emulator.stack.push(-77);
                             } else {
                                 emulator.stack.push(count);
@@ -476,6 +755,54 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                         }
 
                         if let Some(_) = emulator.fds.remove(&(fd as usize)) {
+                            emulator.stack.push(0);
+                        } else {
+                            emulator.stack.push(-77);
+                        }
+                    }
+                    32 => {
+                        // SYS_dup
+                        let oldfd;
+
+                        if let Some(f) = emulator.stack.pop() {
+                            oldfd = f;
+                        } else {
+                            panic!("stack underflow");
+                        }
+
+                        if let Some(stream) = emulator.fds.get(&(oldfd as usize)) {
+                            let stream = stream.clone();
+                            let fd = emulator.insert_fd(stream);
+                            emulator.stack.push(fd as i64);
+                        } else {
+                            emulator.stack.push(-77);
+                        }
+                    }
+                    33 => {
+                        // SYS_dup2
+                        let oldfd;
+                        let newfd;
+
+                        if let Some(f) = emulator.stack.pop() {
+                            oldfd = f;
+                        } else {
+                            panic!("stack underflow");
+                        }
+
+                        if let Some(f) = emulator.stack.pop() {
+                            newfd = f;
+                        } else {
+                            panic!("stack underflow");
+                        }
+
+                        // whatever `newfd` was is closed first
+                        if newfd < 0 {
+                            emulator.stack.push(-77);
+                        } else if let Some(stream) = emulator.fds.get(&(oldfd as usize)) {
+                            let stream = stream.clone();
+                            emulator.fds.insert(newfd as usize, stream);
+                            emulator.stack.push(newfd);
+                        } else {
                             emulator.stack.push(-77);
                         }
                     }
@@ -487,7 +814,8 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                         } else {
                             panic!("stack underflow");
                         }
-                        exit(code);
+                        emulator.exit_code = Some(code);
+                        return;
                     }
                     257 => {
                         // SYS_openat
@@ -535,7 +863,7 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                         let mut filename = String::new();
                         {
                             let mut bytes: Vec<u8> = vec![];
-                            for i in nameptr..(emulator.memory.len() as i64) {
+                            for i in nameptr..(emulator.memory.capacity() as i64) {
                                 if emulator.memory[i as usize] == 0 {
                                     break;
                                 }
@@ -552,91 +880,81 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                             }
                         }
 
-                        static O_CREAT: u64 = 64;
+                        static O_ACCMODE: u64 = 3;
                         static O_RDONLY: u64 = 0;
                         static O_WRONLY: u64 = 1;
-                        static O_TRUNC: u64 = 512;
                         static O_RDWR: u64 = 2;
+                        static O_CREAT: u64 = 64;
+                        static O_TRUNC: u64 = 512;
+                        static O_APPEND: u64 = 1024;
 
+                        let accmode = flags & O_ACCMODE;
                         let istrunc = (flags & O_TRUNC) == O_TRUNC;
-                        let iswriteonly = (flags & O_WRONLY) == O_WRONLY;
                         let iscreate = (flags & O_CREAT) == O_CREAT;
-                        let isreadonly = (flags & O_RDONLY) == O_RDONLY;
-                        let isrdwr = (flags & O_RDWR) == O_RDWR;
-
-                        if !istrunc && !iswriteonly && !iscreate && !isreadonly && isrdwr {
+                        let isappend = (flags & O_APPEND) == O_APPEND;
+
+                        let (read, write) = if accmode == O_RDONLY {
+                            (true, false)
+                        } else if accmode == O_WRONLY {
+                            (false, true)
+                        } else if accmode == O_RDWR {
+                            (true, true)
+                        } else {
                             emulator.stack.push(-1);
                             emulator.ip += 1;
                             continue;
-                        }
+                        };
 
-                        let file = if iswriteonly {
-                            let f = OpenOptions::new()
-                                .create(iscreate)
-                                .truncate(istrunc)
-                                .write(true)
-                                .read(false)
-                                .open(filename);
-                            if f.is_err() {
-                                emulator.stack.push(-2);
-                                emulator.ip += 1;
-                                continue;
-                            }
-                            f.unwrap()
-                        } else if isreadonly {
-                            let f = OpenOptions::new()
-                                .create(iscreate)
-                                .truncate(istrunc)
-                                .write(false)
-                                .read(true)
-                                .open(filename);
-                            if f.is_err() {
-                                emulator.stack.push(-2);
-                                emulator.ip += 1;
-                                continue;
-                            }
-                            f.unwrap()
-                        } else if isrdwr {
-                            let f = OpenOptions::new()
-                                .create(iscreate)
-                                .truncate(istrunc)
-                                .write(true)
-                                .read(true)
-                                .open(filename);
-                            if f.is_err() {
-                                emulator.stack.push(-2);
-                                emulator.ip += 1;
-                                continue;
-                            }
-                            f.unwrap()
+                        let file = if let Ok(f) = OpenOptions::new()
+                            .create(iscreate)
+                            .truncate(istrunc)
+                            .write(write)
+                            .append(isappend)
+                            .read(read)
+                            .open(filename)
+                        {
+                            f
                         } else {
-                            panic!("unreachable");
+                            emulator.stack.push(-2);
+                            emulator.ip += 1;
+                            continue;
                         };
 
-                        let fd = emulator.fds.len();
-
-                        let mut stream;
-                        {
-                            stream = Stream::new();
-                            stream.file = Some(file);
-                        }
+                        let stream = if let Ok(s) = Stream::from_file(file) {
+                            s
+                        } else {
+                            emulator.stack.push(-24);
+                            emulator.ip += 1;
+                            continue;
+                        };
 
-                        emulator.fds.insert(fd, stream);
+                        let fd = emulator.insert_fd(Rc::new(RefCell::new(stream)));
                         emulator.stack.push(fd as i64);
                     }
                     _ => panic!("unsupported syscall: {}", syscall_number),
                 }
+
+                // a syscall can be given more arguments than it uses, which the
+                // registers would just ignore, so they are dropped here too
+                if let Some(result) = emulator.stack.pop() {
+                    emulator.stack.truncate(base);
+                    emulator.stack.push(result);
+                }
                 emulator.ip += 1;
             }
             IrInstructionKind::AllocVariable => emulator.ip += 1,
             IrInstructionKind::Load8 => {
                 let addr;
                 if let Some(a) = emulator.stack.pop() {
-                    addr = a;
+                    addr = a + op.operand.integer;
                 } else {
                     panic!("stack underflow");
                 }
 
+                if addr < 0 || addr as usize >= emulator.memory.capacity() {
+                    panic!("out of bounds memory access at address {}", addr);
+                }
+
                 emulator.stack.push(emulator.memory[addr as usize] as i64);
                 emulator.ip += 1;
             }
@@ -645,7 +963,7 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                 let value;
 
                 if let Some(a) = emulator.stack.pop() {
-                    addr = a;
+                    addr = a + op.operand.integer;
                 } else {
                     panic!("stack underflow");
                 }
@@ -656,13 +974,27 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                     panic!("stack underflow");
                 }
 
+                emulator.check_store_fits(value, 1);
                 emulator.memory[addr as usize] = value as u8;
                 emulator.ip += 1;
             }
-            IrInstructionKind::Load16 => {
+            IrInstructionKind::Load8Signed => {
+                let addr;
+                if let Some(a) = emulator.stack.pop() {
+                    addr = a + op.operand.integer;
+                } else {
+                    panic!("stack underflow");
+                }
+
+                emulator
+                    .stack
+                    .push(emulator.memory[addr as usize] as i8 as i64);
+                emulator.ip += 1;
+            }
+            IrInstructionKind::Load16Signed => {
                 let addr;
                 if let Some(a) = emulator.stack.pop() {
-                    addr = a;
+                    addr = a + op.operand.integer;
                 } else {
                     panic!("stack underflow");
                 }
@@ -674,17 +1006,48 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                 emulator.stack.push(i16::from_le_bytes(bytes) as i64);
                 emulator.ip += 1;
             }
+            IrInstructionKind::Load32Signed => {
+                let addr;
+                if let Some(a) = emulator.stack.pop() {
+                    addr = a + op.operand.integer;
+                } else {
+                    panic!("stack underflow");
+                }
+
+                let mut bytes: [u8; 4] = [0, 0, 0, 0];
+                for i in 0..4 {
+                    bytes[i] = emulator.memory[(addr as usize) + i];
+                }
+                emulator.stack.push(i32::from_le_bytes(bytes) as i64);
+                emulator.ip += 1;
+            }
+            IrInstructionKind::Load16 => {
+                let addr;
+                if let Some(a) = emulator.stack.pop() {
+                    addr = a + op.operand.integer;
+                } else {
+                    panic!("stack underflow");
+                }
+
+                let mut bytes: [u8; 2] = [0, 0];
+                for i in 0..2 {
+                    bytes[i] = emulator.memory[(addr as usize) + i];
+                }
+                emulator.stack.push(u16::from_le_bytes(bytes) as i64);
+                emulator.ip += 1;
+            }
             IrInstructionKind::Store16 => {
                 let mut addr;
                 let value: i16;
 
                 if let Some(a) = emulator.stack.pop() {
-                    addr = a;
+                    addr = a + op.operand.integer;
                 } else {
                     panic!("stack underflow");
                 }
 
                 if let Some(v) = emulator.stack.pop() {
+                    emulator.check_store_fits(v, 2);
                     value = v as i16;
                 } else {
                     panic!("stack underflow");
@@ -701,7 +1064,7 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
             IrInstructionKind::Load32 => {
                 let addr;
                 if let Some(a) = emulator.stack.pop() {
-                    addr = a;
+                    addr = a + op.operand.integer;
                 } else {
                     panic!("stack underflow");
                 }
@@ -710,7 +1073,7 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                 for i in 0..4 {
                     bytes[i] = emulator.memory[(addr as usize) + i];
                 }
-                emulator.stack.push(i32::from_le_bytes(bytes) as i64);
+                emulator.stack.push(u32::from_le_bytes(bytes) as i64);
                 emulator.ip += 1;
             }
             IrInstructionKind::Store32 => {
@@ -718,12 +1081,13 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                 let value: i32;
 
                 if let Some(a) = emulator.stack.pop() {
-                    addr = a;
+                    addr = a + op.operand.integer;
                 } else {
                     panic!("stack underflow");
                 }
 
                 if let Some(v) = emulator.stack.pop() {
+                    emulator.check_store_fits(v, 4);
                     value = v as i32;
                 } else {
                     panic!("stack underflow");
@@ -740,7 +1104,7 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
             IrInstructionKind::Load64 => {
                 let addr;
                 if let Some(a) = emulator.stack.pop() {
-                    addr = a;
+                    addr = a + op.operand.integer;
                 } else {
                     panic!("stack underflow");
                 }
@@ -757,7 +1121,7 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                 let value: i64;
 
                 if let Some(a) = emulator.stack.pop() {
-                    addr = a;
+                    addr = a + op.operand.integer;
                 } else {
                     panic!("stack underflow");
                 }
@@ -787,7 +1151,28 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
             IrInstructionKind::Jump => {
                 emulator.ip = op.operand.integer as usize;
             }
+            IrInstructionKind::JumpTable => {
+                // to one of the jumps that follow, the last one
+                // being for the values that are out of range
+                let entries = op.operand.integer;
+                if let Some(a) = emulator.stack.pop() {
+                    let entry = if (0..entries).contains(&a) {
+                        a
+                    } else {
+                        entries
+                    };
+                    emulator.ip += 1 + entry as usize;
+                } else {
+                    panic!("stack underflow");
+                }
+            }
             IrInstructionKind::Nop => emulator.ip += 1,
+            IrInstructionKind::Drop => {
+                if emulator.stack.pop().is_none() {
+                    panic!("stack underflow");
+                }
+                emulator.ip += 1;
+            }
             IrInstructionKind::If => {
                 if let Some(a) = emulator.stack.pop() {
                     if a != 0 {
@@ -1006,6 +1391,14 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                 }
                 emulator.ip += 1;
             }
+            IrInstructionKind::LogicalNot => {
+                if let Some(v) = emulator.stack.pop() {
+                    emulator.stack.push((v == 0) as i64);
+                } else {
+                    panic!("stack underflow");
+                }
+                emulator.ip += 1;
+            }
             IrInstructionKind::PushString => {
                 let string = escape_string(op.operand.string);
                 if let Some(addr) = emulator.find_string_in_memory(string) {
@@ -1017,6 +1410,11 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
                 emulator.ip += 1;
             }
             IrInstructionKind::Call => {
+                // the same depth that fits in the return stack of an executable
+                if emulator.ret_stack.len() >= X86_64_RET_STACK_CAP / 8 {
+                    eprintln!("ERROR: Return stack overflow, the calls are nested too deep");
+                    exit(1);
+                }
                 emulator.ret_stack.push(emulator.ip + 1);
                 emulator.ip = op.operand.integer as usize;
             }
@@ -1043,18 +1441,247 @@ pub fn emulate_program(ir: IrProgram, emulator: &mut Emulator) {
             }
         }
     }
+
+    if let Some(p) = previous {
+        emulator.track_type(&p);
+    }
 }
 
-pub fn emulate_file(config: Config) -> Result<()> {
-    let ir = compile_file_into_ir(config.clone().input)?;
-    let mut emulator = Emulator::new();
+pub fn emulate_file(config: Config) -> std::result::Result<(), LoispError> {
+    let mut context = LoispContext::new();
+    if config.no_std_search {
+        context.search_paths.clear();
+    }
+    context.allow_redefine = config.allow_redefine;
+    let mut timings = Timings::new();
+    let ir = compile_file_into_ir(config.clone().input, &mut context, &mut timings)?;
+    if config.stats {
+        print_stats(&ir, &context, None);
+    }
+    if config.dump_context {
+        dump_context(&context);
+    }
+    if !config.silent && !ir.calls_exit() {
+        // a compiled program exits with 0 at the end, and so does this.
+        // It goes to stderr to keep stdout for the output of the program
+        eprintln!(
+            "[INFO] `{}` never calls `exit`, so its exit code will be 0",
+            config.input
+        );
+    }
+
+    let mut emulator = emulator_for_config(&config);
+    if config.profile {
+        emulator.profile = Some(vec![0; ir.instructions.len()]);
+    }
+    let code = timings.time("emulating", || emulate_ir(&ir, &mut emulator))?;
+    if config.timings {
+        timings.print();
+    }
+    if config.count {
+        print_info!("COUNT", "{} IR instructions were executed", emulator.steps);
+    }
+    if config.stats {
+        print_info!(
+            "STATS",
+            "Emulated memory: {} of {} bytes allocated",
+            emulator.memory.allocated(),
+            emulator.memory.capacity()
+        );
+    }
+    if let Some(profile) = &emulator.profile {
+        print_profile(&ir, profile);
+    }
+
+    if let Some(code) = code {
+        exit(code);
+    }
+    Ok(())
+}
+
+pub static PROFILE_LENGTH: usize = 10;
+
+// the instructions that were executed the most, with the
+// same addresses and operands as `disasm`
+pub fn print_profile(ir: &IrProgram, profile: &[u64]) {
+    let mut hottest: Vec<(usize, u64)> = profile.iter().copied().enumerate().collect();
+    // the earlier instruction goes first when the counts are the same
+    hottest.sort_by(|(a_ip, a), (b_ip, b)| b.cmp(a).then(a_ip.cmp(b_ip)));
+
+    for (ip, count) in hottest.into_iter().take(PROFILE_LENGTH) {
+        if count == 0 {
+            break;
+        }
+        let i = &ir.instructions[ip];
+        let instruction = format!("{:?} {}", i.kind, i.operand_as_string());
+        print_info!(
+            "PROFILE",
+            "{:>10} {:>5}: {}",
+            count,
+            ip,
+            instruction.trim_end()
+        );
+    }
+}
+
+pub fn emulator_for_config(config: &Config) -> Emulator {
+    let mut emulator = Emulator::with_buffers(config.emulator.clone());
+
+    emulator.args.push(config.input.clone());
+    for a in &config.run.args {
+        emulator.args.push(a.clone());
+    }
+
+    emulator
+}
+
+// makes sure that the emulation can't go past the end of
+// the program, where going to the end itself just stops it
+pub fn check_jump_targets(ir: &IrProgram) -> std::result::Result<(), EmulatorError> {
+    let count = ir.instructions.len();
+    for (ip, i) in ir.instructions.iter().enumerate() {
+        if let IrInstructionKind::Jump | IrInstructionKind::If | IrInstructionKind::Call = i.kind {
+            if i.operand.integer < 0 || i.operand.integer as usize > count {
+                return Err(EmulatorError::InvalidJumpTarget(
+                    ip,
+                    i.kind.clone(),
+                    i.operand.integer,
+                    count,
+                ));
+            }
+        }
 
-    emulator.args.push(config.input);
-    for a in config.run.args {
-        emulator.args.push(a);
+        // its jumps, and then the one for the values out of its range
+        if i.kind == IrInstructionKind::JumpTable {
+            let last = ip as i64 + 1 + i.operand.integer;
+            if i.operand.integer < 0 || last >= count as i64 {
+                return Err(EmulatorError::InvalidJumpTarget(
+                    ip,
+                    i.kind.clone(),
+                    last,
+                    count,
+                ));
+            }
+        }
     }
 
-    emulator.init(ir.clone());
-    emulate_program(ir.clone(), &mut emulator);
     Ok(())
 }
+
+// returns the exit code if the program called `exit`
+pub fn emulate_ir(
+    ir: &IrProgram,
+    emulator: &mut Emulator,
+) -> std::result::Result<Option<i32>, LoispError> {
+    check_jump_targets(ir)?;
+    emulator.init(ir.clone())?;
+    emulate_program(ir.clone(), emulator);
+    Ok(emulator.exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+
+    fn compile(source: &str) -> IrProgram {
+        let instructions = try_parse(source, "test.loisp".to_string()).unwrap();
+        lower_instructions(instructions, &mut LoispContext::new()).unwrap()
+    }
+
+    #[test]
+    fn capture_output_keeps_stdout_and_stderr_apart() {
+        let ir = compile("(print 42) (eprint 5) (print 7)");
+        let mut emulator = Emulator::new();
+        let (out, err) = emulator.capture_output();
+
+        emulate_ir(&ir, &mut emulator).unwrap();
+
+        assert_eq!(out.bytes(), b"42\n7\n");
+        assert_eq!(err.bytes(), b"5\n");
+    }
+
+    #[test]
+    fn stream_reads_and_writes_through_its_sides() {
+        let out = SharedBuffer::new();
+        let mut stream = Stream::with_reader(Cursor::new(b"hello".to_vec()));
+        stream.writer = Some(Box::new(out.clone()));
+
+        assert_eq!(stream.read(3).unwrap(), b"hel");
+        assert_eq!(stream.read(8).unwrap(), b"lo");
+        assert_eq!(stream.read(8).unwrap(), b"");
+        stream.write(b"bye").unwrap();
+        assert_eq!(out.bytes(), b"bye");
+    }
+
+    #[test]
+    fn stream_without_a_side_fails() {
+        let mut stream = Stream::new();
+
+        assert!(stream.read(1).is_err());
+        assert!(stream.write(b"x").is_err());
+    }
+
+    fn instruction(kind: IrInstructionKind, operand: i64) -> IrInstruction {
+        IrInstruction {
+            kind,
+            operand: IrInstructionValue::new().integer(operand),
+        }
+    }
+
+    #[test]
+    fn check_jump_targets_allows_the_end_of_the_program() {
+        let mut ir = IrProgram::new();
+        ir.push(instruction(IrInstructionKind::Jump, 2));
+        ir.push(instruction(IrInstructionKind::Call, 0));
+
+        assert!(check_jump_targets(&ir).is_ok());
+    }
+
+    #[test]
+    fn check_jump_targets_rejects_targets_outside_the_program() {
+        for (kind, target) in [
+            (IrInstructionKind::Jump, 3),
+            (IrInstructionKind::If, -1),
+            (IrInstructionKind::Call, 10),
+        ] {
+            let mut ir = IrProgram::new();
+            ir.push(instruction(IrInstructionKind::PushInteger, 1));
+            ir.push(instruction(kind, target));
+
+            assert!(matches!(
+                check_jump_targets(&ir),
+                Err(EmulatorError::InvalidJumpTarget(1, _, t, 2)) if t == target
+            ));
+        }
+    }
+
+    #[test]
+    fn check_jump_targets_rejects_a_jump_table_past_the_end() {
+        // a table of 2 entries needs 3 jumps after it
+        let mut ir = IrProgram::new();
+        ir.push(instruction(IrInstructionKind::JumpTable, 2));
+        ir.push(instruction(IrInstructionKind::Jump, 4));
+        ir.push(instruction(IrInstructionKind::Jump, 4));
+
+        assert!(check_jump_targets(&ir).is_err());
+
+        ir.push(instruction(IrInstructionKind::Jump, 4));
+        assert!(check_jump_targets(&ir).is_ok());
+    }
+
+    #[test]
+    fn memory_allocates_the_pages_that_are_written() {
+        let mut memory = Memory::new(3 * PAGE_SIZE);
+        assert_eq!(memory.capacity(), 3 * PAGE_SIZE);
+        assert_eq!(memory.allocated(), 0);
+
+        // reading doesn't allocate anything
+        assert_eq!(memory[PAGE_SIZE + 1], 0);
+        assert_eq!(memory.allocated(), 0);
+
+        memory[PAGE_SIZE + 1] = 7;
+        assert_eq!(memory[PAGE_SIZE + 1], 7);
+        assert_eq!(memory.allocated(), PAGE_SIZE);
+    }
+}
@@ -1,12 +1,17 @@
+use super::common::*;
+use super::emulator::*;
 use super::ir::*;
 use super::lexer::*;
 use super::parser::*;
 use super::types::*;
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error;
 use std::fmt;
 use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 static DEFAULT_SEARCH_PATHS: [&str; 4] = [".", "..", "./std", "../std"];
 
@@ -16,10 +21,12 @@ pub enum LoispError {
     TooMuchParameters(LexerToken),
     MismatchedTypes(LexerToken),
     ParserError(ParserError),
+    EmulatorError(EmulatorError),
     StandardError(io::Error),
     VariableNotFound(LexerToken),
     VariableRedefinition(LexerToken),
     NoDeclarationsInLoops(LexerToken),
+    NoDeclarationsInConditionals(LexerToken),
     NoDeclarationsInMacros(LexerToken),
     MemoryRedefinition(LexerToken),
     MemoryNotFound(LexerToken),
@@ -32,6 +39,31 @@ pub enum LoispError {
     NoDeclarationsInFunctions(LexerToken),
     UnsupportedAtCompileTime(LexerToken),
     InvalidMacroAtCompileTime(LexerToken),
+    UnknownPragma(LexerToken),
+    InvalidWidth(LexerToken),
+    InvalidOffset(LexerToken),
+    InvalidArrayLength(LexerToken),
+    PrintNotInteger(LexerToken, LoispDatatype),
+    UnknownFileMode(LexerToken),
+    FileNotFound(String),
+    CouldNotReadFile(String, io::Error),
+    UnsupportedHost,
+    AssertionFailed(LexerToken, String),
+    ReturnOutsideFunction(LexerToken),
+    ReturnsOutsideFunctionDeclaration(LexerToken),
+    UnknownType(LexerToken),
+    ReturnTypeMismatch(LexerToken, String, LoispDatatype, LoispDatatype),
+    ArrayNotFound(LexerToken),
+    CannotAssignBareWord(LexerToken),
+    StructRedefinition(LexerToken),
+    StructNotFound(LexerToken),
+    FieldRedefinition(LexerToken),
+    FieldNotFound(LexerToken),
+    IndexOutOfBounds(LexerToken, i64, usize),
+    IncludeNotFound(LexerToken, Vec<String>),
+    CaseOutsideSwitch(LexerToken),
+    ExpectedCase(LexerToken),
+    DuplicateCase(LexerToken),
 }
 
 impl fmt::Display for LoispError {
@@ -53,7 +85,8 @@ impl fmt::Display for LoispError {
                 token.location, token.value.string
             )?,
             Self::ParserError(error) => write!(f, "{}", error)?,
-            Self::StandardError(error) => write!(f, "ERROR: {:?}", error)?,
+            Self::EmulatorError(error) => write!(f, "{}", error)?,
+            Self::StandardError(error) => write!(f, "ERROR: {}", error)?,
             Self::VariableNotFound(token) => write!(
                 f,
                 "{}: ERROR: Variable not found: `{}`",
@@ -69,6 +102,11 @@ impl fmt::Display for LoispError {
                 "{}: ERROR: Declarations in loops are not allowed",
                 token.location
             )?,
+            Self::NoDeclarationsInConditionals(token) => write!(
+                f,
+                "{}: ERROR: Declarations in conditionals are not allowed",
+                token.location
+            )?,
             Self::NoDeclarationsInMacros(token) => write!(
                 f,
                 "{}: ERROR: Declarations inside macros are not allowed",
@@ -129,11 +167,143 @@ impl fmt::Display for LoispError {
                 "{}: ERROR: Invalid macro in compile time evaluation",
                 token.location
             )?,
+            Self::UnknownPragma(token) => write!(
+                f,
+                "{}: ERROR: Unknown pragma: `{}`",
+                token.location, token.value.string
+            )?,
+            Self::InvalidWidth(token) => write!(
+                f,
+                "{}: ERROR: Invalid width, expected one of 1, 2, 4 or 8",
+                token.location
+            )?,
+            Self::InvalidOffset(token) => write!(
+                f,
+                "{}: ERROR: Invalid offset, expected a constant that fits in 32 bits",
+                token.location
+            )?,
+            Self::InvalidArrayLength(token) => write!(
+                f,
+                "{}: ERROR: Invalid array length, expected a positive constant that isn't too large",
+                token.location
+            )?,
+            Self::PrintNotInteger(token, typ) => write!(
+                f,
+                "{}: ERROR: `{}` only prints integers, but got a {:?}. Use `puts` from `std.loisp` to print a string, or `castint` to print an address",
+                token.location, token.value.string, typ
+            )?,
+            Self::UnknownFileMode(token) => write!(
+                f,
+                "{}: ERROR: Unknown file mode: `{}`, expected one of `read`, `write`, `readwrite` or `append`",
+                token.location, token.value.string
+            )?,
+            Self::FileNotFound(path) => write!(f, "ERROR: file not found: {}", path)?,
+            Self::ReturnOutsideFunction(token) => write!(
+                f,
+                "{}: ERROR: `return` can only be used inside functions",
+                token.location
+            )?,
+            Self::ReturnsOutsideFunctionDeclaration(token) => write!(
+                f,
+                "{}: ERROR: `returns` can only be used as the second parameter of `defun`",
+                token.location
+            )?,
+            Self::UnknownType(token) => write!(
+                f,
+                "{}: ERROR: Unknown type: `{}`, expected one of `int`, `str`, `ptr` or `nothing`",
+                token.location, token.value.string
+            )?,
+            Self::ReturnTypeMismatch(token, name, expected, found) => write!(
+                f,
+                "{}: ERROR: Function `{}` is declared to return `{:?}`, but returns `{:?}`",
+                token.location, name, expected, found
+            )?,
+            Self::CannotAssignBareWord(token) => write!(
+                f,
+                "{}: ERROR: Can't assign the word `{}` to a variable, did you mean `(getvar {})`?",
+                token.location, token.value.string, token.value.string
+            )?,
+            Self::ArrayNotFound(token) => write!(
+                f,
+                "{}: ERROR: Array not found: `{}`",
+                token.location, token.value.string
+            )?,
+            Self::StructRedefinition(token) => write!(
+                f,
+                "{}: ERROR: Struct redefinition: `{}`",
+                token.location, token.value.string
+            )?,
+            Self::StructNotFound(token) => write!(
+                f,
+                "{}: ERROR: Struct not found: `{}`",
+                token.location, token.value.string
+            )?,
+            Self::FieldRedefinition(token) => write!(
+                f,
+                "{}: ERROR: Field redefinition: `{}`",
+                token.location, token.value.string
+            )?,
+            Self::FieldNotFound(token) => write!(
+                f,
+                "{}: ERROR: Field not found: `{}`",
+                token.location, token.value.string
+            )?,
+            Self::IndexOutOfBounds(token, index, count) => write!(
+                f,
+                "{}: ERROR: Index {} is out of bounds for an array of {} elements",
+                token.location, index, count
+            )?,
+            Self::IncludeNotFound(token, tried) => write!(
+                f,
+                "{}: ERROR: Could not find `{}` to include, tried: {}",
+                token.location,
+                token.value.string,
+                tried.join(", ")
+            )?,
+            Self::CaseOutsideSwitch(token) => write!(
+                f,
+                "{}: ERROR: `{}` can only be used inside of `switch`",
+                token.location, token.value.string
+            )?,
+            Self::ExpectedCase(token) => write!(
+                f,
+                "{}: ERROR: Expected `case` or `default` inside of `switch`",
+                token.location
+            )?,
+            Self::DuplicateCase(token) => write!(
+                f,
+                "{}: ERROR: This `switch` already has a `{}` for that value",
+                token.location, token.value.string
+            )?,
+            Self::AssertionFailed(token, message) => write!(
+                f,
+                "{}: ERROR: Assertion failed: {}",
+                token.location, message
+            )?,
+            Self::UnsupportedHost => write!(
+                f,
+                "ERROR: Unsupported host, executables can only be generated on Unix"
+            )?,
+            Self::CouldNotReadFile(path, error) => {
+                write!(f, "ERROR: could not read file {}: {}", path, error)?
+            }
         }
         Ok(())
     }
 }
 
+impl error::Error for LoispError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::ParserError(error) => Some(error),
+            Self::EmulatorError(error) => Some(error),
+            Self::StandardError(error) => Some(error),
+            Self::CouldNotReadFile(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
 impl From<std::io::Error> for LoispError {
     fn from(e: std::io::Error) -> Self {
         Self::StandardError(e)
@@ -146,9 +316,18 @@ impl From<ParserError> for LoispError {
     }
 }
 
+impl From<EmulatorError> for LoispError {
+    fn from(error: EmulatorError) -> Self {
+        Self::EmulatorError(error)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum LoispInstructionType {
     Print,
+    EPrint,
+    PrintHex,
+    PrintBin,
     Plus,
     Minus,
     Multiplication,
@@ -177,7 +356,11 @@ pub enum LoispInstructionType {
     Store16,
     Load8,
     Store8,
+    Load32Signed,
+    Load16Signed,
+    Load8Signed,
     Alloc,
+    AllocStr,
     GetMem,
     CastPointer,
     CastInt,
@@ -198,6 +381,42 @@ pub enum LoispInstructionType {
     Argc,
     Argv,
     Envp,
+    Pragma,
+    Load,
+    Store,
+    FOpen,
+    FRead,
+    FWrite,
+    FClose,
+    StrCat,
+    LogicalNot,
+    StrByte,
+    ArgAt,
+    Assert,
+    Return,
+    Returns,
+    Switch,
+    Case,
+    Default,
+    Array,
+    ArrayGet,
+    ArraySet,
+    Struct,
+    StructField,
+    Decrement,
+    Field,
+    PlusAssign,
+    MinusAssign,
+    MultiplicationAssign,
+    DivisionAssign,
+    Load64Offset,
+    Store64Offset,
+    Load32Offset,
+    Store32Offset,
+    Load16Offset,
+    Store16Offset,
+    Load8Offset,
+    Store8Offset,
 }
 
 #[derive(Debug, Clone)]
@@ -244,6 +463,39 @@ impl LoispValue {
     pub fn size(&self, context: &mut LoispContext) -> usize {
         self.datatype(context).unwrap().size()
     }
+
+    // returns the token of the variable name if this value is
+    // a `getvar` of a variable that doesn't exist
+    pub fn unresolved_variable(&self, context: &LoispContext) -> Option<LexerToken> {
+        if let Some(i) = &self.instruction_return {
+            if i.kind != LoispInstructionType::GetVar || i.parameters.len() != 1 {
+                return None;
+            }
+
+            if let Some(name) = &i.parameters[0].word {
+                if !context.variables.contains_key(name)
+                    && !context.local_variables.contains_key(name)
+                {
+                    return Some(i.parameters[0].token.clone());
+                }
+            }
+        }
+        None
+    }
+
+    // whether one of the words in the value, at any depth, is in `words`
+    pub fn uses_any_word(&self, words: &HashSet<String>) -> bool {
+        if let Some(word) = &self.word {
+            if words.contains(word) {
+                return true;
+            }
+        }
+
+        match &self.instruction_return {
+            Some(i) => i.parameters.iter().any(|p| p.uses_any_word(words)),
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -256,12 +508,17 @@ pub struct LoispFunction {
 pub struct LoispMacro {
     pub id: usize,
     pub program: IrProgram,
+    pub typ: LoispDatatype,
+    pub body: Vec<LoispValue>,
+    // the value of `iota` when the body was lowered
+    pub iota: i64,
 }
 
 #[derive(Debug, Clone)]
 pub struct LoispVariable {
     pub id: usize,
     pub value: LoispValue,
+    pub size: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -270,7 +527,27 @@ pub struct LoispMemory {
     pub alloc: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct LoispArray {
+    pub memory: usize,
+    pub index: usize,
+    pub elemsize: usize,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoispField {
+    pub name: String,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoispStruct {
+    pub fields: Vec<LoispField>,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
 pub struct LoispContext {
     pub variables: HashMap<String, LoispVariable>,
     pub memories: HashMap<String, LoispMemory>,
@@ -282,6 +559,14 @@ pub struct LoispContext {
     pub variable_count: usize,
     pub inside_fun: bool,
     pub iota: i64,
+    pub once_files: HashSet<String>,
+    pub block_types: HashMap<usize, LoispDatatype>,
+    pub switch_types: HashMap<usize, LoispDatatype>,
+    pub returns: Vec<(usize, LexerToken, LoispDatatype)>,
+    pub arrays: HashMap<String, LoispArray>,
+    pub structs: HashMap<String, LoispStruct>,
+    pub search_paths: Vec<String>,
+    pub allow_redefine: bool,
 }
 
 impl LoispContext {
@@ -297,11 +582,37 @@ impl LoispContext {
             variable_count: 0,
             inside_fun: false,
             iota: 0,
+            once_files: HashSet::new(),
+            block_types: HashMap::new(),
+            switch_types: HashMap::new(),
+            returns: vec![],
+            arrays: HashMap::new(),
+            structs: HashMap::new(),
+            search_paths: DEFAULT_SEARCH_PATHS.iter().map(|p| p.to_string()).collect(),
+            allow_redefine: false,
+        }
+    }
+
+    pub fn get_array(&self, string: &str) -> Option<LoispArray> {
+        // the memory of the array follows the usual scoping rules,
+        // so the array is only visible while its memory is
+        let array = self.arrays.get(string)?;
+        let memory = self
+            .local_memories
+            .get(string)
+            .or_else(|| self.memories.get(string))?;
+
+        if memory.id == array.memory {
+            Some(array.clone())
+        } else {
+            None
         }
     }
 
     pub fn insert_memory(&mut self, string: String, value: LoispMemory) {
-        self.memories.insert(string, value);
+        if self.memories.insert(string.clone(), value).is_some() {
+            self.relower_macros(&string);
+        }
         self.memory_count += 1;
     }
 
@@ -311,7 +622,9 @@ impl LoispContext {
     }
 
     pub fn insert_variable(&mut self, string: String, value: LoispVariable) {
-        self.variables.insert(string, value);
+        if self.variables.insert(string.clone(), value).is_some() {
+            self.relower_macros(&string);
+        }
         self.variable_count += 1;
     }
 
@@ -319,6 +632,40 @@ impl LoispContext {
         self.local_variables.insert(string, value);
         self.variable_count += 1;
     }
+
+    // macros are lowered when they are defined, so after a global variable
+    // or memory is replaced with `--allow-redefine` they would keep using
+    // the old one. Only the macros that use it, directly or by expanding
+    // another of them, are lowered again, with the `iota` that they had.
+    // A macro whose body no longer lowers, because it used something
+    // local to a function, is left as it was
+    fn relower_macros(&mut self, replaced: &str) {
+        let mut names: Vec<(usize, String)> = self
+            .macros
+            .iter()
+            .map(|(name, m)| (m.id, name.clone()))
+            .collect();
+        // a macro can only expand the ones defined before it
+        names.sort();
+
+        let iota = self.iota;
+        let mut words = HashSet::from([replaced.to_string()]);
+        for (_, name) in names {
+            let maccro = self.macros[&name].clone();
+            if !maccro.body.iter().any(|p| p.uses_any_word(&words)) {
+                continue;
+            }
+
+            self.iota = maccro.iota;
+            if let Ok((program, typ)) = lower_macro_body(&maccro.body, self) {
+                let maccro = self.macros.get_mut(&name).unwrap();
+                maccro.program = program;
+                maccro.typ = typ;
+            }
+            words.insert(name);
+        }
+        self.iota = iota;
+    }
 }
 
 pub fn value_size_as_store_instruction(s: usize, ir: &mut IrProgram) {
@@ -389,146 +736,914 @@ pub fn value_size_as_load_instruction(s: usize, ir: &mut IrProgram) {
     }
 }
 
-pub fn ir_push(inst: IrInstruction, ir: &mut IrProgram) {
-    ir.push(inst);
-}
+pub fn value_as_width(p: &LoispValue, context: &mut LoispContext) -> Result<usize, LoispError> {
+    if p.datatype(context).unwrap() != LoispDatatype::Integer {
+        return Err(LoispError::MismatchedTypes(p.token.clone()));
+    }
 
-pub fn push_value(
-    p: LoispValue,
-    ir: &mut IrProgram,
-    context: &mut LoispContext,
-) -> Result<(), LoispError> {
-    if let Some(_) = p.instruction_return {
-        p.instruction_return.unwrap().to_ir(ir, context)?;
+    let width = if p.is_instruction_return() {
+        p.clone()
+            .instruction_return
+            .unwrap()
+            .evaluate_at_compile_time(context)?
     } else {
-        match p.datatype(context) {
-            Some(LoispDatatype::Integer) => ir_push(
-                IrInstruction {
-                    kind: IrInstructionKind::PushInteger,
-                    operand: IrInstructionValue::new().integer(p.integer.unwrap()),
-                },
-                ir,
-            ),
-            Some(LoispDatatype::Pointer) => ir_push(
-                IrInstruction {
-                    kind: IrInstructionKind::PushInteger,
-                    operand: IrInstructionValue::new().integer(p.integer.unwrap()),
-                },
-                ir,
-            ),
-            Some(LoispDatatype::Word) => {
-                return Err(LoispError::ParserError(ParserError::InvalidSyntax(
-                    p.token.clone(),
-                )))
-            }
-            Some(LoispDatatype::String) => ir_push(
-                IrInstruction {
-                    kind: IrInstructionKind::PushString,
-                    operand: IrInstructionValue::new().string(p.string.unwrap()),
-                },
-                ir,
-            ),
-            Some(LoispDatatype::Nothing) => {}
-            None => panic!("unreachable"),
-        }
+        p.integer.unwrap()
+    };
+
+    match width {
+        1 | 2 | 4 | 8 => Ok(width as usize),
+        _ => Err(LoispError::InvalidWidth(p.token.clone())),
     }
-    Ok(())
 }
 
-#[derive(Debug, Clone)]
-pub struct LoispInstruction {
-    pub kind: LoispInstructionType,
-    pub parameters: Vec<LoispValue>,
-    pub token: LexerToken,
+// the offset is part of the load or store instruction in
+// the assembly, so it must be known at compile time
+pub fn value_as_offset(p: &LoispValue, context: &mut LoispContext) -> Result<i64, LoispError> {
+    if p.datatype(context).unwrap() != LoispDatatype::Integer {
+        return Err(LoispError::MismatchedTypes(p.token.clone()));
+    }
+
+    let offset = if p.is_instruction_return() {
+        p.clone()
+            .instruction_return
+            .unwrap()
+            .evaluate_at_compile_time(context)?
+    } else {
+        p.integer.unwrap()
+    };
+
+    if offset < i32::MIN as i64 || offset > i32::MAX as i64 {
+        return Err(LoispError::InvalidOffset(p.token.clone()));
+    }
+
+    Ok(offset)
 }
 
-impl LoispInstruction {
-    pub fn new(t: LexerToken) -> LoispInstruction {
-        LoispInstruction {
-            kind: LoispInstructionType::Nop,
-            parameters: vec![],
-            token: t,
-        }
+pub fn file_mode_as_flags(p: &LoispValue, context: &mut LoispContext) -> Result<i64, LoispError> {
+    static O_RDONLY: i64 = 0;
+    static O_WRONLY: i64 = 1;
+    static O_RDWR: i64 = 2;
+    static O_CREAT: i64 = 64;
+    static O_TRUNC: i64 = 512;
+    static O_APPEND: i64 = 1024;
+
+    if p.datatype(context).unwrap() != LoispDatatype::Word {
+        return Err(LoispError::MismatchedTypes(p.token.clone()));
     }
 
-    pub fn evaluate_at_compile_time(&self, context: &mut LoispContext) -> Result<i64, LoispError> {
-        match self.kind {
-            LoispInstructionType::Expand => {
-                if self.parameters.len() < 1 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
-                }
+    match p.word.as_ref().unwrap().as_str() {
+        "read" => Ok(O_RDONLY),
+        "write" => Ok(O_CREAT | O_WRONLY | O_TRUNC),
+        "readwrite" => Ok(O_CREAT | O_RDWR),
+        "append" => Ok(O_CREAT | O_WRONLY | O_APPEND),
+        _ => Err(LoispError::UnknownFileMode(p.token.clone())),
+    }
+}
 
-                if self.parameters.len() > 1 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
+// the branches of a `switch`, each with the value of its `case`, or
+// `None` for its `default`, and the value that is lowered when it matches
+pub fn switch_branches(
+    instruction: &LoispInstruction,
+    context: &mut LoispContext,
+) -> Result<Vec<(Option<i64>, LoispValue)>, LoispError> {
+    use LoispInstructionType::*;
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
+    let mut branches: Vec<(Option<i64>, LoispValue)> = vec![];
 
-                if let Some(maccro) = context
-                    .macros
-                    .get(self.parameters[0].word.as_ref().unwrap())
-                {
-                    if let Some(last) = maccro.program.instructions.last() {
-                        if last.kind != IrInstructionKind::PushInteger {
-                            if last.kind == IrInstructionKind::CastInt
-                                || last.kind == IrInstructionKind::CastPointer
-                            {
-                                let mut insts = maccro.program.instructions.clone();
-                                insts.pop();
-                                if let Some(r#final) = insts.last() {
-                                    if r#final.kind != IrInstructionKind::PushInteger {
-                                        return Ok(r#final.operand.integer);
-                                    } else {
-                                        return Err(LoispError::InvalidMacroAtCompileTime(
-                                            self.token.clone(),
-                                        ));
-                                    }
-                                } else {
-                                    return Err(LoispError::InvalidMacroAtCompileTime(
-                                        self.token.clone(),
-                                    ));
-                                }
-                            } else {
-                                return Err(LoispError::InvalidMacroAtCompileTime(
-                                    self.token.clone(),
-                                ));
-                            }
-                        } else {
-                            return Ok(last.operand.integer);
-                        }
-                    } else {
-                        return Err(LoispError::InvalidMacroAtCompileTime(self.token.clone()));
-                    }
-                } else {
-                    return Err(LoispError::MacroNotFound(self.parameters[0].token.clone()));
-                }
-            }
-            LoispInstructionType::Plus => {
-                if self.parameters.len() < 2 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
-                }
+    for p in &instruction.parameters[1..] {
+        let branch = match &p.instruction_return {
+            Some(i) if i.kind == Case || i.kind == Default => i,
+            _ => return Err(LoispError::ExpectedCase(p.token.clone())),
+        };
 
-                if self.parameters.len() > 2 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
+        let arity = if branch.kind == Case { 2 } else { 1 };
+        if branch.parameters.len() < arity {
+            return Err(LoispError::NotEnoughParameters(branch.token.clone()));
+        }
 
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
-                {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
+        if branch.parameters.len() > arity {
+            return Err(LoispError::TooMuchParameters(branch.token.clone()));
+        }
 
-                let a = if self.parameters[0].is_instruction_return() {
-                    self.parameters[0]
-                        .clone()
+        let value = if branch.kind == Case {
+            let v = &branch.parameters[0];
+            if v.datatype(context).unwrap() != LoispDatatype::Integer {
+                return Err(LoispError::MismatchedTypes(v.token.clone()));
+            }
+
+            if v.is_instruction_return() {
+                Some(
+                    v.clone()
                         .instruction_return
                         .unwrap()
-                        .evaluate_at_compile_time(context)?
-                } else {
-                    self.parameters[0].clone().integer.unwrap()
-                };
+                        .evaluate_at_compile_time(context)?,
+                )
+            } else {
+                Some(v.integer.unwrap())
+            }
+        } else {
+            None
+        };
+
+        if branches.iter().any(|(v, _)| *v == value) {
+            return Err(LoispError::DuplicateCase(branch.token.clone()));
+        }
+
+        // every branch is lowered, so a declaration in one of them would
+        // be visible after the `switch` no matter which one gets executed
+        let body = branch.parameters[arity - 1].clone();
+        if let Some(i) = &body.instruction_return {
+            if matches!(i.kind, SetVar | Alloc | AllocStr | Array | Macro | DefFun) {
+                return Err(LoispError::NoDeclarationsInConditionals(body.token.clone()));
+            }
+        }
+
+        branches.push((value, body));
+    }
+
+    Ok(branches)
+}
+
+// like with `if`, the value of a `switch` can only be used when it has a
+// `default` and all of its branches leave the same type on the stack
+pub fn switch_branches_type(types: &[LoispDatatype], has_default: bool) -> LoispDatatype {
+    match types.first() {
+        Some(t) if has_default && types.iter().all(|typ| typ == t) => t.clone(),
+        _ => LoispDatatype::Nothing,
+    }
+}
+
+pub fn switch_type(instruction: &LoispInstruction, context: &mut LoispContext) -> LoispDatatype {
+    use LoispInstructionType::*;
+
+    // the variables defined inside of the branches are out
+    // of scope once it's lowered, so its type is remembered
+    if let Some(typ) = context.switch_types.get(&instruction.id) {
+        return typ.clone();
+    }
+
+    let mut types = vec![];
+    let mut has_default = false;
+    for p in instruction.parameters.iter().skip(1) {
+        let branch = match &p.instruction_return {
+            Some(i) if i.kind == Case || i.kind == Default => i,
+            _ => return LoispDatatype::Nothing,
+        };
+        has_default |= branch.kind == Default;
+
+        match branch.parameters.last() {
+            Some(body) => types.push(body.datatype(context).unwrap()),
+            None => return LoispDatatype::Nothing,
+        }
+    }
+
+    switch_branches_type(&types, has_default)
+}
+
+// a jump table has an entry for every value between the smallest and the
+// largest case, so it's only worth it when most of those entries are cases
+pub fn switch_is_dense(cases: &[i64]) -> bool {
+    if let (Some(min), Some(max)) = (cases.iter().min(), cases.iter().max()) {
+        let range = *max as i128 - *min as i128 + 1;
+        cases.len() >= 3 && range <= 2 * cases.len() as i128
+    } else {
+        false
+    }
+}
+
+pub fn value_as_declared_return_type(
+    p: &LoispValue,
+    context: &mut LoispContext,
+) -> Result<Option<LoispDatatype>, LoispError> {
+    let declaration = match &p.instruction_return {
+        Some(i) if i.kind == LoispInstructionType::Returns => i,
+        _ => return Ok(None),
+    };
+
+    if declaration.parameters.len() < 1 {
+        return Err(LoispError::NotEnoughParameters(declaration.token.clone()));
+    }
+
+    if declaration.parameters.len() > 1 {
+        return Err(LoispError::TooMuchParameters(declaration.token.clone()));
+    }
+
+    Ok(Some(value_as_datatype(
+        &declaration.parameters[0],
+        &declaration.token,
+        context,
+    )?))
+}
+
+pub fn value_as_datatype(
+    p: &LoispValue,
+    token: &LexerToken,
+    context: &mut LoispContext,
+) -> Result<LoispDatatype, LoispError> {
+    if p.datatype(context).unwrap() != LoispDatatype::Word {
+        return Err(LoispError::MismatchedTypes(token.clone()));
+    }
+
+    match p.word.as_ref().unwrap().as_str() {
+        "int" => Ok(LoispDatatype::Integer),
+        "str" => Ok(LoispDatatype::String),
+        "ptr" => Ok(LoispDatatype::Pointer),
+        "nothing" => Ok(LoispDatatype::Nothing),
+        _ => Err(LoispError::UnknownType(p.token.clone())),
+    }
+}
+
+// expects the condition on the stack, and exits with 1 after
+// writing the message to stderr if it is false
+pub fn push_runtime_check(message: String, ir: &mut IrProgram) {
+    let length = escape_string(message.clone()).len() as i64;
+
+    let if_addr = ir.instructions.len();
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::If,
+            operand: IrInstructionValue::new(),
+        },
+        ir,
+    );
+
+    let jump_addr = ir.instructions.len();
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::Jump,
+            operand: IrInstructionValue::new(),
+        },
+        ir,
+    );
+
+    ir.instructions[if_addr].operand =
+        IrInstructionValue::new().integer(ir.instructions.len() as i64);
+
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushInteger,
+            operand: IrInstructionValue::new().integer(length),
+        },
+        ir,
+    );
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushString,
+            operand: IrInstructionValue::new().string(message),
+        },
+        ir,
+    );
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushInteger,
+            operand: IrInstructionValue::new().integer(2),
+        },
+        ir,
+    );
+    push_syscall(1, 3, ir);
+    drop_unused_value(LoispDatatype::Integer, ir);
+
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushInteger,
+            operand: IrInstructionValue::new().integer(1),
+        },
+        ir,
+    );
+    push_syscall(60, 1, ir);
+    drop_unused_value(LoispDatatype::Integer, ir);
+
+    ir.instructions[jump_addr].operand =
+        IrInstructionValue::new().integer(ir.instructions.len() as i64);
+
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::Nop,
+            operand: IrInstructionValue::new(),
+        },
+        ir,
+    );
+}
+
+// pushes the address of the element at `index`, checking that
+// the index is in bounds at compile time when it is a constant,
+// and at runtime otherwise
+pub fn push_array_element_address(
+    name: &LoispValue,
+    index: &LoispValue,
+    token: &LexerToken,
+    ir: &mut IrProgram,
+    context: &mut LoispContext,
+) -> Result<LoispArray, LoispError> {
+    if name.datatype(context).unwrap() != LoispDatatype::Word
+        || index.datatype(context).unwrap() != LoispDatatype::Integer
+    {
+        return Err(LoispError::MismatchedTypes(token.clone()));
+    }
+
+    let array = match context.get_array(name.word.as_ref().unwrap()) {
+        Some(a) => a,
+        None => return Err(LoispError::ArrayNotFound(name.token.clone())),
+    };
+
+    let constant = match &index.instruction_return {
+        Some(i) => i.evaluate_at_compile_time(context).ok(),
+        None => index.integer,
+    };
+
+    if let Some(i) = constant {
+        if i < 0 || i as usize >= array.count {
+            return Err(LoispError::IndexOutOfBounds(
+                index.token.clone(),
+                i,
+                array.count,
+            ));
+        }
+    }
+
+    // the index is kept in a memory of its own, so the
+    // expression that computes it is only evaluated once
+    push_value(index.clone(), ir, context)?;
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushMemory,
+            operand: IrInstructionValue::new().integer(array.index as i64),
+        },
+        ir,
+    );
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::Store64,
+            operand: IrInstructionValue::new(),
+        },
+        ir,
+    );
+
+    let push_index = |ir: &mut IrProgram| {
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::PushMemory,
+                operand: IrInstructionValue::new().integer(array.index as i64),
+            },
+            ir,
+        );
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::Load64,
+                operand: IrInstructionValue::new(),
+            },
+            ir,
+        );
+    };
+
+    if constant.is_none() {
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::PushInteger,
+                operand: IrInstructionValue::new().integer(0),
+            },
+            ir,
+        );
+        push_index(ir);
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::GreaterEqual,
+                operand: IrInstructionValue::new(),
+            },
+            ir,
+        );
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::PushInteger,
+                operand: IrInstructionValue::new().integer(array.count as i64),
+            },
+            ir,
+        );
+        push_index(ir);
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::Less,
+                operand: IrInstructionValue::new(),
+            },
+            ir,
+        );
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::And,
+                operand: IrInstructionValue::new(),
+            },
+            ir,
+        );
+
+        let message = format!("{}: Index out of bounds\\n", token.location);
+        push_runtime_check(message, ir);
+    }
+
+    push_index(ir);
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushInteger,
+            operand: IrInstructionValue::new().integer(array.elemsize as i64),
+        },
+        ir,
+    );
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::Multiplication,
+            operand: IrInstructionValue::new(),
+        },
+        ir,
+    );
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushMemory,
+            operand: IrInstructionValue::new().integer(array.memory as i64),
+        },
+        ir,
+    );
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::Plus,
+            operand: IrInstructionValue::new(),
+        },
+        ir,
+    );
+
+    Ok(array)
+}
+
+// adds (or subtracts, depending on `operation`) the step given as
+// second parameter, or 1, to the variable given as first parameter
+pub fn push_variable_step(
+    instruction: &LoispInstruction,
+    operation: IrInstructionKind,
+    ir: &mut IrProgram,
+    context: &mut LoispContext,
+) -> Result<(), LoispError> {
+    if instruction.parameters.len() < 1 {
+        return Err(LoispError::NotEnoughParameters(instruction.token.clone()));
+    }
+
+    if instruction.parameters.len() > 2 {
+        return Err(LoispError::TooMuchParameters(instruction.token.clone()));
+    }
+
+    if instruction.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+        return Err(LoispError::MismatchedTypes(instruction.token.clone()));
+    }
+
+    let name = instruction.parameters[0].word.as_ref().unwrap();
+    let var = match context
+        .local_variables
+        .get(name)
+        .or_else(|| context.variables.get(name))
+    {
+        Some(v) => v.clone(),
+        None => {
+            return Err(LoispError::VariableNotFound(
+                instruction.parameters[0].token.clone(),
+            ))
+        }
+    };
+
+    if var.value.datatype(context).unwrap() != LoispDatatype::Integer {
+        return Err(LoispError::MismatchedTypes(instruction.token.clone()));
+    }
+
+    if let Some(step) = instruction.parameters.get(1) {
+        if step.datatype(context).unwrap() != LoispDatatype::Integer {
+            return Err(LoispError::MismatchedTypes(instruction.token.clone()));
+        }
+        push_value(step.clone(), ir, context)?;
+    } else {
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::PushInteger,
+                operand: IrInstructionValue::new().integer(1),
+            },
+            ir,
+        );
+    }
+
+    let size = var.size;
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushVariable,
+            operand: IrInstructionValue::new().integer(var.id as i64),
+        },
+        ir,
+    );
+    value_size_as_load_instruction(size, ir);
+    ir_push(
+        IrInstruction {
+            kind: operation,
+            operand: IrInstructionValue::new(),
+        },
+        ir,
+    );
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushVariable,
+            operand: IrInstructionValue::new().integer(var.id as i64),
+        },
+        ir,
+    );
+    value_size_as_store_instruction(size, ir);
+
+    Ok(())
+}
+
+// names that collide with an instruction would look like
+// a call to that instruction when they are used
+pub fn warn_if_name_is_reserved(p: &LoispValue) {
+    if token_to_instruction_kind(p.token.clone()).is_ok() {
+        eprintln!(
+            "{}: WARNING: `{}` is also the name of an instruction",
+            p.token.location, p.token.value.string
+        );
+    }
+}
+
+// with `--allow-redefine` the new definition replaces
+// the previous one, instead of being an error
+pub fn redefinition(
+    p: &LoispValue,
+    context: &LoispContext,
+    error: LoispError,
+) -> Result<(), LoispError> {
+    if !context.allow_redefine {
+        return Err(error);
+    }
+    eprintln!(
+        "{}: WARNING: `{}` was already defined, this definition replaces it",
+        p.token.location, p.token.value.string
+    );
+    Ok(())
+}
+
+// the instructions of a macro and the type of its last value
+pub fn lower_macro_body(
+    body: &[LoispValue],
+    context: &mut LoispContext,
+) -> Result<(IrProgram, LoispDatatype), LoispError> {
+    let mut ops = IrProgram::new();
+    let mut typ = LoispDatatype::Nothing;
+    for p in body {
+        if p.is_instruction_return() {
+            if p.instruction_return.clone().unwrap().kind == LoispInstructionType::SetVar
+                || p.clone().instruction_return.unwrap().kind == LoispInstructionType::Alloc
+                || p.clone().instruction_return.unwrap().kind == LoispInstructionType::AllocStr
+                || p.clone().instruction_return.unwrap().kind == LoispInstructionType::Array
+                || p.clone().instruction_return.unwrap().kind == LoispInstructionType::Macro
+                || p.clone().instruction_return.unwrap().kind == LoispInstructionType::DefFun
+            {
+                return Err(LoispError::NoDeclarationsInMacros(p.token.clone()));
+            }
+        }
+        push_value(p.clone(), &mut ops, context)?;
+        // the type comes from the instruction and not from the
+        // IR, which doesn't know what a `call` returns
+        typ = p.datatype(context).unwrap();
+    }
+    Ok((ops, typ))
+}
+
+pub fn push_syscall(number: i64, parameter_count: i64, ir: &mut IrProgram) {
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::PushInteger,
+            operand: IrInstructionValue::new().integer(number),
+        },
+        ir,
+    );
+    ir_push(
+        IrInstruction {
+            kind: IrInstructionKind::Syscall,
+            operand: IrInstructionValue::new().integer(parameter_count + 1),
+        },
+        ir,
+    );
+}
+
+pub fn value_as_constant_string(p: &LoispValue, context: &LoispContext) -> Option<String> {
+    if let Some(i) = &p.instruction_return {
+        match i.kind {
+            LoispInstructionType::StrCat => {
+                let mut string = String::new();
+                for p in &i.parameters {
+                    string.push_str(value_as_constant_string(p, context)?.as_str());
+                }
+                Some(string)
+            }
+            LoispInstructionType::Expand => {
+                let name = i.parameters.first()?.word.as_ref()?;
+                match context.macros.get(name)?.program.instructions.as_slice() {
+                    [op] if op.kind == IrInstructionKind::PushString => {
+                        Some(op.operand.string.clone())
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    } else {
+        p.string.clone()
+    }
+}
+
+pub fn drop_unused_value(typ: LoispDatatype, ir: &mut IrProgram) {
+    if typ != LoispDatatype::Nothing {
+        ir_push(
+            IrInstruction {
+                kind: IrInstructionKind::Drop,
+                operand: IrInstructionValue::new(),
+            },
+            ir,
+        );
+    }
+}
+
+pub fn ir_push(inst: IrInstruction, ir: &mut IrProgram) {
+    ir.push(inst);
+}
+
+pub fn push_value(
+    p: LoispValue,
+    ir: &mut IrProgram,
+    context: &mut LoispContext,
+) -> Result<(), LoispError> {
+    if let Some(_) = p.instruction_return {
+        p.instruction_return.unwrap().to_ir(ir, context)?;
+    } else {
+        match p.datatype(context) {
+            Some(LoispDatatype::Integer) => ir_push(
+                IrInstruction {
+                    kind: IrInstructionKind::PushInteger,
+                    operand: IrInstructionValue::new().integer(p.integer.unwrap()),
+                },
+                ir,
+            ),
+            Some(LoispDatatype::Pointer) => ir_push(
+                IrInstruction {
+                    kind: IrInstructionKind::PushInteger,
+                    operand: IrInstructionValue::new().integer(p.integer.unwrap()),
+                },
+                ir,
+            ),
+            Some(LoispDatatype::Word) => {
+                return Err(LoispError::ParserError(ParserError::InvalidSyntax(
+                    p.token.clone(),
+                )))
+            }
+            Some(LoispDatatype::String) => ir_push(
+                IrInstruction {
+                    kind: IrInstructionKind::PushString,
+                    operand: IrInstructionValue::new().string(p.string.unwrap()),
+                },
+                ir,
+            ),
+            Some(LoispDatatype::Nothing) => {}
+            None => panic!("unreachable"),
+        }
+    }
+    Ok(())
+}
+
+// every parsed instruction gets a different id, even when it comes
+// from another file or another line of the REPL at the same location
+static INSTRUCTION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct LoispInstruction {
+    pub id: usize,
+    pub kind: LoispInstructionType,
+    pub parameters: Vec<LoispValue>,
+    pub token: LexerToken,
+    pub end: LexerLocation,
+}
+
+impl LoispInstruction {
+    pub fn new(t: LexerToken) -> LoispInstruction {
+        LoispInstruction {
+            id: INSTRUCTION_COUNT.fetch_add(1, Ordering::Relaxed),
+            kind: LoispInstructionType::Nop,
+            parameters: vec![],
+            end: t.location.clone(),
+            token: t,
+        }
+    }
+
+    pub fn spans(&self, r: i64, c: i64) -> bool {
+        let start = &self.token.location;
+        (start.r, start.c) <= (r, c) && (r, c) <= (self.end.r, self.end.c)
+    }
+
+    // returns the innermost instruction that spans the given position
+    pub fn instruction_at(&self, r: i64, c: i64) -> Option<&LoispInstruction> {
+        if !self.spans(r, c) {
+            return None;
+        }
+
+        for p in &self.parameters {
+            if let Some(i) = &p.instruction_return {
+                if let Some(inner) = i.instruction_at(r, c) {
+                    return Some(inner);
+                }
+            }
+        }
+
+        Some(self)
+    }
+
+    pub fn evaluate_at_compile_time(&self, context: &mut LoispContext) -> Result<i64, LoispError> {
+        match self.kind {
+            LoispInstructionType::Expand => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                if let Some(maccro) = context
+                    .macros
+                    .get(self.parameters[0].word.as_ref().unwrap())
+                {
+                    if let Some(last) = maccro.program.instructions.last() {
+                        if last.kind != IrInstructionKind::PushInteger {
+                            if last.kind == IrInstructionKind::CastInt
+                                || last.kind == IrInstructionKind::CastPointer
+                            {
+                                let mut insts = maccro.program.instructions.clone();
+                                insts.pop();
+                                if let Some(r#final) = insts.last() {
+                                    if r#final.kind != IrInstructionKind::PushInteger {
+                                        return Ok(r#final.operand.integer);
+                                    } else {
+                                        return Err(LoispError::InvalidMacroAtCompileTime(
+                                            self.token.clone(),
+                                        ));
+                                    }
+                                } else {
+                                    return Err(LoispError::InvalidMacroAtCompileTime(
+                                        self.token.clone(),
+                                    ));
+                                }
+                            } else {
+                                return Err(LoispError::InvalidMacroAtCompileTime(
+                                    self.token.clone(),
+                                ));
+                            }
+                        } else {
+                            return Ok(last.operand.integer);
+                        }
+                    } else {
+                        return Err(LoispError::InvalidMacroAtCompileTime(self.token.clone()));
+                    }
+                } else {
+                    return Err(LoispError::MacroNotFound(self.parameters[0].token.clone()));
+                }
+            }
+            LoispInstructionType::Plus => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                let a = if self.parameters[0].is_instruction_return() {
+                    self.parameters[0]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?
+                } else {
+                    self.parameters[0].clone().integer.unwrap()
+                };
+
+                let b = if self.parameters[1].is_instruction_return() {
+                    self.parameters[1]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?
+                } else {
+                    self.parameters[1].clone().integer.unwrap()
+                };
+
+                return Ok(a + b);
+            }
+            LoispInstructionType::Minus => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                let a = if self.parameters[0].is_instruction_return() {
+                    self.parameters[0]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?
+                } else {
+                    self.parameters[0].clone().integer.unwrap()
+                };
+
+                let b = if self.parameters[1].is_instruction_return() {
+                    self.parameters[1]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?
+                } else {
+                    self.parameters[1].clone().integer.unwrap()
+                };
+
+                return Ok(a - b);
+            }
+            LoispInstructionType::Multiplication => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                let a = if self.parameters[0].is_instruction_return() {
+                    self.parameters[0]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?
+                } else {
+                    self.parameters[0].clone().integer.unwrap()
+                };
+
+                let b = if self.parameters[1].is_instruction_return() {
+                    self.parameters[1]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?
+                } else {
+                    self.parameters[1].clone().integer.unwrap()
+                };
+
+                return Ok(a * b);
+            }
+            LoispInstructionType::Division => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                let a = if self.parameters[0].is_instruction_return() {
+                    self.parameters[0]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?
+                } else {
+                    self.parameters[0].clone().integer.unwrap()
+                };
 
                 let b = if self.parameters[1].is_instruction_return() {
                     self.parameters[1]
@@ -537,49 +1652,589 @@ impl LoispInstruction {
                         .unwrap()
                         .evaluate_at_compile_time(context)?
                 } else {
-                    self.parameters[1].clone().integer.unwrap()
+                    self.parameters[1].clone().integer.unwrap()
+                };
+
+                return Ok(a / b);
+            }
+            _ => return Err(LoispError::UnsupportedAtCompileTime(self.token.clone())),
+        }
+    }
+
+    pub fn return_type(&self, context: &mut LoispContext) -> LoispDatatype {
+        use LoispDatatype::*;
+        match self.kind {
+            LoispInstructionType::Print => Nothing,
+            LoispInstructionType::EPrint => Nothing,
+            LoispInstructionType::PrintHex => Nothing,
+            LoispInstructionType::PrintBin => Nothing,
+            LoispInstructionType::Nop => Nothing,
+            LoispInstructionType::Plus => Integer,
+            LoispInstructionType::Minus => Integer,
+            LoispInstructionType::Multiplication => Integer,
+            LoispInstructionType::Division => Integer,
+            LoispInstructionType::Mod => Integer,
+            LoispInstructionType::Syscall => Integer,
+            LoispInstructionType::SetVar => Nothing,
+            LoispInstructionType::GetVar => {
+                let var = context
+                    .variables
+                    .get(self.parameters[0].word.as_ref().unwrap());
+                let local_var = context
+                    .local_variables
+                    .get(self.parameters[0].word.as_ref().unwrap());
+                match local_var.or(var) {
+                    Some(v) => v.value.clone().datatype(context).unwrap(),
+                    None => Nothing,
+                }
+            }
+            LoispInstructionType::ChVar => Nothing,
+            LoispInstructionType::While => Nothing,
+            LoispInstructionType::Equal => Integer,
+            LoispInstructionType::NotEqual => Integer,
+            LoispInstructionType::If => {
+                if self.parameters.len() != 3 {
+                    return Nothing;
+                }
+
+                // the value of an `if` can only be used when both
+                // branches leave the same type on the stack
+                let then_type = self.parameters[1].datatype(context).unwrap();
+                if then_type == self.parameters[2].datatype(context).unwrap() {
+                    then_type
+                } else {
+                    Nothing
+                }
+            }
+            LoispInstructionType::Block => {
+                // the variables defined inside of the block are out of
+                // scope once it's lowered, so its type is remembered
+                if let Some(typ) = context.block_types.get(&self.id) {
+                    return typ.clone();
+                }
+
+                let (last, rest) = match self.parameters.split_last() {
+                    Some(split) => split,
+                    None => return Nothing,
+                };
+
+                // before it's lowered, the variables that the block defines
+                // are added for a moment, so that its last value can use them
+                let variables = context.variables.clone();
+                let local_variables = context.local_variables.clone();
+                for p in rest {
+                    let i = match &p.instruction_return {
+                        Some(i) if i.kind == LoispInstructionType::SetVar => i,
+                        _ => continue,
+                    };
+                    let name = i.parameters.first().and_then(|p| p.word.clone());
+                    if let (Some(name), Some(value)) = (name, i.parameters.get(1)) {
+                        let variable = LoispVariable {
+                            id: 0,
+                            value: value.clone(),
+                            size: 0,
+                        };
+                        if context.inside_fun {
+                            context.local_variables.insert(name, variable);
+                        } else {
+                            context.variables.insert(name, variable);
+                        }
+                    }
+                }
+
+                let typ = last.datatype(context).unwrap();
+                context.variables = variables;
+                context.local_variables = local_variables;
+                typ
+            }
+            LoispInstructionType::Less => Integer,
+            LoispInstructionType::Greater => Integer,
+            LoispInstructionType::LessEqual => Integer,
+            LoispInstructionType::GreaterEqual => Integer,
+            LoispInstructionType::PtrTo => Pointer,
+            LoispInstructionType::Load64 => Integer,
+            LoispInstructionType::Store64 => Nothing,
+            LoispInstructionType::Load32 => Integer,
+            LoispInstructionType::Store32 => Nothing,
+            LoispInstructionType::Load16 => Integer,
+            LoispInstructionType::Store16 => Nothing,
+            LoispInstructionType::Load8 => Integer,
+            LoispInstructionType::Load32Signed => Integer,
+            LoispInstructionType::Load64Offset
+            | LoispInstructionType::Load32Offset
+            | LoispInstructionType::Load16Offset
+            | LoispInstructionType::Load8Offset => Integer,
+            LoispInstructionType::Store64Offset
+            | LoispInstructionType::Store32Offset
+            | LoispInstructionType::Store16Offset
+            | LoispInstructionType::Store8Offset => Nothing,
+            LoispInstructionType::Load16Signed => Integer,
+            LoispInstructionType::Load8Signed => Integer,
+            LoispInstructionType::Store8 => Nothing,
+            LoispInstructionType::Alloc => Nothing,
+            LoispInstructionType::AllocStr => Nothing,
+            LoispInstructionType::GetMem => Pointer,
+            LoispInstructionType::CastInt => Integer,
+            LoispInstructionType::CastPointer => Pointer,
+            LoispInstructionType::ShiftLeft => Integer,
+            LoispInstructionType::ShiftRight => Integer,
+            LoispInstructionType::Or => Integer,
+            LoispInstructionType::And => Integer,
+            LoispInstructionType::Not => Integer,
+            LoispInstructionType::Macro => Nothing,
+            LoispInstructionType::Expand => {
+                let maccro = context
+                    .macros
+                    .get(self.parameters[0].word.as_ref().unwrap());
+                if let Some(maccro) = maccro {
+                    return maccro.typ.clone();
+                } else {
+                    return Nothing;
+                }
+            }
+            LoispInstructionType::Pop => Nothing,
+            LoispInstructionType::Include => Nothing,
+            LoispInstructionType::DefFun => Nothing,
+            LoispInstructionType::Call => {
+                if let Some(function) = context
+                    .functions
+                    .get(self.parameters[0].word.as_ref().unwrap())
+                {
+                    return function.clone().typ;
+                } else {
+                    return Nothing;
+                }
+            }
+            LoispInstructionType::Increment => {
+                // incrementing a variable doesn't return anything,
+                // unlike incrementing the `iota`
+                if self
+                    .parameters
+                    .first()
+                    .and_then(|p| p.word.as_ref())
+                    .is_some()
+                {
+                    return Nothing;
+                }
+                Integer
+            }
+            LoispInstructionType::Decrement => Nothing,
+            LoispInstructionType::PlusAssign
+            | LoispInstructionType::MinusAssign
+            | LoispInstructionType::MultiplicationAssign
+            | LoispInstructionType::DivisionAssign => Nothing,
+            LoispInstructionType::Reset => Integer,
+            LoispInstructionType::TypeOf => String,
+            LoispInstructionType::Argc => Integer,
+            LoispInstructionType::Argv => Pointer,
+            LoispInstructionType::Envp => Pointer,
+            LoispInstructionType::Pragma => Nothing,
+            LoispInstructionType::Load => Integer,
+            LoispInstructionType::Store => Nothing,
+            LoispInstructionType::FOpen => Integer,
+            LoispInstructionType::FRead => Integer,
+            LoispInstructionType::FWrite => Integer,
+            LoispInstructionType::FClose => Integer,
+            LoispInstructionType::StrCat => String,
+            LoispInstructionType::LogicalNot => Integer,
+            LoispInstructionType::StrByte => Integer,
+            LoispInstructionType::ArgAt => Pointer,
+            LoispInstructionType::Assert => Nothing,
+            LoispInstructionType::Return => Nothing,
+            LoispInstructionType::Returns => Nothing,
+            LoispInstructionType::Switch => switch_type(self, context),
+            LoispInstructionType::Case => Nothing,
+            LoispInstructionType::Default => Nothing,
+            LoispInstructionType::Array => Nothing,
+            LoispInstructionType::ArrayGet => Integer,
+            LoispInstructionType::ArraySet => Nothing,
+            LoispInstructionType::Struct => Nothing,
+            LoispInstructionType::StructField => Nothing,
+            LoispInstructionType::Field => Pointer,
+        }
+    }
+
+    pub fn push_parameters(
+        &self,
+        ir: &mut IrProgram,
+        context: &mut LoispContext,
+        reverse: bool,
+    ) -> Result<(), LoispError> {
+        if !reverse {
+            for p in self.parameters.iter() {
+                push_value(p.clone(), ir, context)?;
+            }
+        } else {
+            for p in self.parameters.iter().rev() {
+                push_value(p.clone(), ir, context)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_variables(&self, context: &LoispContext) -> Result<(), LoispError> {
+        use LoispInstructionType::*;
+
+        // instructions that lower their parameters in sequence may define
+        // a variable in one parameter and use it in the next one, so only
+        // the values that get type checked up front are verified here
+        let parameters = match self.kind {
+            Block | While | DefFun | Macro => return Ok(()),
+            If | Switch => &self.parameters[..self.parameters.len().min(1)],
+            _ => &self.parameters[..],
+        };
+
+        for p in parameters {
+            if let Some(token) = p.unresolved_variable(context) {
+                return Err(LoispError::VariableNotFound(token));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_ir(&self, ir: &mut IrProgram, context: &mut LoispContext) -> Result<(), LoispError> {
+        use LoispInstructionType::*;
+
+        self.check_variables(context)?;
+
+        match self.kind {
+            Print | EPrint | PrintHex | PrintBin => {
+                self.push_parameters(ir, context, true)?;
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                let typ = self.parameters[0].datatype(context).unwrap();
+                if typ == LoispDatatype::Nothing {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                // the address would be printed, which is never what was meant
+                if typ == LoispDatatype::String || typ == LoispDatatype::Pointer {
+                    return Err(LoispError::PrintNotInteger(self.token.clone(), typ));
+                }
+
+                ir_push(
+                    IrInstruction {
+                        kind: match self.kind {
+                            EPrint => IrInstructionKind::EPrint,
+                            PrintHex => IrInstructionKind::PrintHex,
+                            PrintBin => IrInstructionKind::PrintBin,
+                            _ => IrInstructionKind::Print,
+                        },
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Plus => {
+                self.push_parameters(ir, context, true)?;
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Plus,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Minus => {
+                self.push_parameters(ir, context, true)?;
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Minus,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Multiplication => {
+                self.push_parameters(ir, context, true)?;
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Multiplication,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Division => {
+                self.push_parameters(ir, context, true)?;
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Division,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Mod => {
+                self.push_parameters(ir, context, true)?;
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Mod,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Syscall => {
+                // checked before anything is lowered, so a syscall
+                // never reaches the backend with more parameters than
+                // there are registers for them: rax for the number and
+                // rdi, rsi, rdx, r10, r8 and r9 for the arguments
+                if syscall_number_as_register(self.parameters.len() as i64 - 1).is_none() {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                self.push_parameters(ir, context, true)?;
+
+                for v in &self.parameters {
+                    if v.datatype(context).unwrap() != LoispDatatype::Integer {
+                        return Err(LoispError::MismatchedTypes(self.token.clone()));
+                    }
+                }
+
+                if let Some(i) = &self.parameters[0].instruction_return {
+                    if i.evaluate_at_compile_time(context).is_err() {
+                        eprintln!(
+                            "{}: WARNING: The syscall number is not a constant, so it can't be validated",
+                            self.parameters[0].token.location
+                        );
+                    }
+                }
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Syscall,
+                        operand: IrInstructionValue::new().integer(self.parameters.len() as i64),
+                    },
+                    ir,
+                );
+            }
+            SetVar => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 3 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                warn_if_name_is_reserved(&self.parameters[0]);
+
+                if self.parameters[1].datatype(context).unwrap() == LoispDatatype::Word {
+                    return Err(LoispError::CannotAssignBareWord(
+                        self.parameters[1].token.clone(),
+                    ));
+                }
+
+                if self.parameters[1].datatype(context).unwrap() == LoispDatatype::Nothing {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                // the width can be given explicitly to make smaller integer variables
+                let size = if self.parameters.len() > 2 {
+                    let width = value_as_width(&self.parameters[2], context)?;
+                    if width != 8
+                        && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                    {
+                        return Err(LoispError::InvalidWidth(self.parameters[2].token.clone()));
+                    }
+                    width
+                } else {
+                    self.parameters[1].size(context)
                 };
 
-                return Ok(a + b);
+                let variable = LoispVariable {
+                    id: context.variable_count,
+                    value: self.parameters[1].clone(),
+                    size,
+                };
+
+                // a local variable can shadow a global one
+                if !context.inside_fun
+                    && context
+                        .variables
+                        .contains_key(&self.parameters[0].clone().word.unwrap())
+                {
+                    redefinition(
+                        &self.parameters[0],
+                        context,
+                        LoispError::VariableRedefinition(self.parameters[0].token.clone()),
+                    )?;
+                }
+
+                if let Some(_) = context
+                    .local_variables
+                    .get(&self.parameters[0].clone().word.unwrap())
+                {
+                    redefinition(
+                        &self.parameters[0],
+                        context,
+                        LoispError::VariableRedefinition(self.parameters[0].token.clone()),
+                    )?;
+                }
+
+                if context.inside_fun {
+                    context.insert_local_variable(
+                        self.parameters[0].clone().word.unwrap(),
+                        variable.clone(),
+                    );
+                } else {
+                    context.insert_variable(
+                        self.parameters[0].clone().word.unwrap(),
+                        variable.clone(),
+                    );
+                }
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::AllocVariable,
+                        operand: IrInstructionValue::new().integer(variable.size as i64),
+                    },
+                    ir,
+                );
+
+                push_value(self.parameters[1].clone(), ir, context)?;
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::PushVariable,
+                        operand: IrInstructionValue::new().integer(variable.clone().id as i64),
+                    },
+                    ir,
+                );
+                value_size_as_store_instruction(variable.size, ir);
             }
-            LoispInstructionType::Minus => {
-                if self.parameters.len() < 2 {
+            GetVar => {
+                if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 2 {
+                if self.parameters.len() > 1 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
-                {
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                let a = if self.parameters[0].is_instruction_return() {
-                    self.parameters[0]
-                        .clone()
-                        .instruction_return
-                        .unwrap()
-                        .evaluate_at_compile_time(context)?
-                } else {
-                    self.parameters[0].clone().integer.unwrap()
-                };
-
-                let b = if self.parameters[1].is_instruction_return() {
-                    self.parameters[1]
-                        .clone()
-                        .instruction_return
-                        .unwrap()
-                        .evaluate_at_compile_time(context)?
+                if let Some(var) = context
+                    .local_variables
+                    .clone()
+                    .get(self.parameters[0].word.as_ref().unwrap())
+                {
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::PushVariable,
+                            operand: IrInstructionValue::new().integer(var.id as i64),
+                        },
+                        ir,
+                    );
+                    value_size_as_load_instruction(var.size, ir);
+                } else if let Some(var) = context
+                    .variables
+                    .clone()
+                    .get(self.parameters[0].word.as_ref().unwrap())
+                {
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::PushVariable,
+                            operand: IrInstructionValue::new().integer(var.id as i64),
+                        },
+                        ir,
+                    );
+                    value_size_as_load_instruction(var.size, ir);
                 } else {
-                    self.parameters[1].clone().integer.unwrap()
-                };
-
-                return Ok(a - b);
+                    return Err(LoispError::VariableNotFound(
+                        self.parameters[0].token.clone(),
+                    ));
+                }
             }
-            LoispInstructionType::Multiplication => {
+            ChVar => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -588,214 +2243,171 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
-                {
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                let a = if self.parameters[0].is_instruction_return() {
-                    self.parameters[0]
-                        .clone()
-                        .instruction_return
-                        .unwrap()
-                        .evaluate_at_compile_time(context)?
+                if let Some(var) = context
+                    .local_variables
+                    .get(self.parameters[0].word.as_ref().unwrap())
+                {
+                    let parameter1 = self.parameters[1].clone();
+                    let variable_value = var.value.clone();
+                    if parameter1.datatype(context).unwrap()
+                        != variable_value.datatype(context).unwrap()
+                    {
+                        return Err(LoispError::MismatchedTypes(self.token.clone()));
+                    }
+                } else if let Some(var) = context
+                    .variables
+                    .get(self.parameters[0].word.as_ref().unwrap())
+                {
+                    let parameter1 = self.parameters[1].clone();
+                    let variable_value = var.value.clone();
+                    if parameter1.datatype(context).unwrap()
+                        != variable_value.datatype(context).unwrap()
+                    {
+                        return Err(LoispError::MismatchedTypes(self.token.clone()));
+                    }
                 } else {
-                    self.parameters[0].clone().integer.unwrap()
-                };
+                    return Err(LoispError::VariableNotFound(
+                        self.parameters[0].token.clone(),
+                    ));
+                }
 
-                let b = if self.parameters[1].is_instruction_return() {
-                    self.parameters[1]
-                        .clone()
-                        .instruction_return
-                        .unwrap()
-                        .evaluate_at_compile_time(context)?
+                let var: LoispVariable;
+
+                if let Some(v) = context
+                    .local_variables
+                    .get(self.parameters[0].word.as_ref().unwrap())
+                {
+                    var = v.clone();
+                } else if let Some(v) = context
+                    .variables
+                    .get(self.parameters[0].word.as_ref().unwrap())
+                {
+                    var = v.clone();
                 } else {
-                    self.parameters[1].clone().integer.unwrap()
-                };
+                    return Err(LoispError::VariableNotFound(
+                        self.parameters[0].token.clone(),
+                    ));
+                }
 
-                return Ok(a * b);
+                push_value(self.parameters.clone().last().unwrap().clone(), ir, context)?;
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::PushVariable,
+                        operand: IrInstructionValue::new().integer(var.id as i64),
+                    },
+                    ir,
+                );
+                value_size_as_store_instruction(var.size, ir);
             }
-            LoispInstructionType::Division => {
+            While => {
+                let loop_begin = ir.instructions.len() as i64;
+
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 2 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
-
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
-                {
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                let a = if self.parameters[0].is_instruction_return() {
-                    self.parameters[0]
-                        .clone()
-                        .instruction_return
-                        .unwrap()
-                        .evaluate_at_compile_time(context)?
-                } else {
-                    self.parameters[0].clone().integer.unwrap()
-                };
+                push_value(self.parameters[0].clone(), ir, context)?;
 
-                let b = if self.parameters[1].is_instruction_return() {
-                    self.parameters[1]
-                        .clone()
-                        .instruction_return
-                        .unwrap()
-                        .evaluate_at_compile_time(context)?
-                } else {
-                    self.parameters[1].clone().integer.unwrap()
-                };
+                let if_addr = ir.instructions.len();
 
-                return Ok(a / b);
-            }
-            _ => return Err(LoispError::UnsupportedAtCompileTime(self.token.clone())),
-        }
-    }
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::If,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
 
-    pub fn return_type(&self, context: &mut LoispContext) -> LoispDatatype {
-        use LoispDatatype::*;
-        match self.kind {
-            LoispInstructionType::Print => Nothing,
-            LoispInstructionType::Nop => Nothing,
-            LoispInstructionType::Plus => Integer,
-            LoispInstructionType::Minus => Integer,
-            LoispInstructionType::Multiplication => Integer,
-            LoispInstructionType::Division => Integer,
-            LoispInstructionType::Mod => Integer,
-            LoispInstructionType::Syscall => Integer,
-            LoispInstructionType::SetVar => Nothing,
-            LoispInstructionType::GetVar => {
-                let var = context
-                    .variables
-                    .get(self.parameters[0].word.as_ref().unwrap());
-                let local_var = context
-                    .local_variables
-                    .get(self.parameters[0].word.as_ref().unwrap());
-                if var.is_none() {
-                    if local_var.is_none() {
-                        return Nothing;
-                    }
-                    return local_var.unwrap().value.clone().datatype(context).unwrap();
-                } else {
-                    return var.unwrap().value.clone().datatype(context).unwrap();
-                }
-            }
-            LoispInstructionType::ChVar => Nothing,
-            LoispInstructionType::While => Nothing,
-            LoispInstructionType::Equal => Integer,
-            LoispInstructionType::NotEqual => Integer,
-            LoispInstructionType::If => Nothing,
-            LoispInstructionType::Block => Nothing,
-            LoispInstructionType::Less => Integer,
-            LoispInstructionType::Greater => Integer,
-            LoispInstructionType::LessEqual => Integer,
-            LoispInstructionType::GreaterEqual => Integer,
-            LoispInstructionType::PtrTo => Pointer,
-            LoispInstructionType::Load64 => Integer,
-            LoispInstructionType::Store64 => Nothing,
-            LoispInstructionType::Load32 => Integer,
-            LoispInstructionType::Store32 => Nothing,
-            LoispInstructionType::Load16 => Integer,
-            LoispInstructionType::Store16 => Nothing,
-            LoispInstructionType::Load8 => Integer,
-            LoispInstructionType::Store8 => Nothing,
-            LoispInstructionType::Alloc => Nothing,
-            LoispInstructionType::GetMem => Pointer,
-            LoispInstructionType::CastInt => Integer,
-            LoispInstructionType::CastPointer => Pointer,
-            LoispInstructionType::ShiftLeft => Integer,
-            LoispInstructionType::ShiftRight => Integer,
-            LoispInstructionType::Or => Integer,
-            LoispInstructionType::And => Integer,
-            LoispInstructionType::Not => Integer,
-            LoispInstructionType::Macro => Nothing,
-            LoispInstructionType::Expand => {
-                let maccro = context
-                    .macros
-                    .get(self.parameters[0].word.as_ref().unwrap());
-                if maccro.is_none() {
-                    return Nothing;
-                } else {
-                    if let Some(last) = maccro.unwrap().program.instructions.last() {
-                        return last.get_loisp_datatype();
-                    } else {
-                        return Nothing;
+                {
+                    let mut parameters = self.parameters.clone();
+                    parameters.remove(0);
+                    for p in parameters {
+                        if p.is_instruction_return() {
+                            if p.clone().instruction_return.unwrap().kind
+                                == LoispInstructionType::SetVar
+                                || p.clone().instruction_return.unwrap().kind
+                                    == LoispInstructionType::Alloc
+                                || p.clone().instruction_return.unwrap().kind
+                                    == LoispInstructionType::AllocStr
+                                || p.clone().instruction_return.unwrap().kind
+                                    == LoispInstructionType::Array
+                                || p.clone().instruction_return.unwrap().kind
+                                    == LoispInstructionType::Macro
+                                || p.clone().instruction_return.unwrap().kind
+                                    == LoispInstructionType::DefFun
+                            {
+                                return Err(LoispError::NoDeclarationsInLoops(p.token));
+                            }
+                        }
+                        push_value(p.clone(), ir, context)?;
+                        drop_unused_value(p.datatype(context).unwrap(), ir);
                     }
                 }
-            }
-            LoispInstructionType::Pop => Nothing,
-            LoispInstructionType::Include => Nothing,
-            LoispInstructionType::DefFun => Nothing,
-            LoispInstructionType::Call => {
-                if let Some(function) = context
-                    .functions
-                    .get(self.parameters[0].word.as_ref().unwrap())
-                {
-                    return function.clone().typ;
-                } else {
-                    return Nothing;
-                }
-            }
-            LoispInstructionType::Increment => Integer,
-            LoispInstructionType::Reset => Integer,
-            LoispInstructionType::TypeOf => String,
-            LoispInstructionType::Argc => Integer,
-            LoispInstructionType::Argv => Pointer,
-            LoispInstructionType::Envp => Pointer,
-        }
-    }
 
-    pub fn push_parameters(
-        &self,
-        ir: &mut IrProgram,
-        context: &mut LoispContext,
-        reverse: bool,
-    ) -> Result<(), LoispError> {
-        if !reverse {
-            for p in self.parameters.iter() {
-                push_value(p.clone(), ir, context)?;
-            }
-        } else {
-            for p in self.parameters.iter().rev() {
-                push_value(p.clone(), ir, context)?;
-            }
-        }
-        Ok(())
-    }
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Jump,
+                        operand: IrInstructionValue::new().integer(loop_begin),
+                    },
+                    ir,
+                );
 
-    pub fn to_ir(&self, ir: &mut IrProgram, context: &mut LoispContext) -> Result<(), LoispError> {
-        use LoispInstructionType::*;
+                let after_end = ir.instructions.len() as i64;
+                ir.instructions[if_addr as usize].operand =
+                    IrInstructionValue::new().integer(after_end + 1);
 
-        match self.kind {
-            Print => {
-                self.push_parameters(ir, context, true)?;
-                if self.parameters.len() < 1 {
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Nop,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Nop,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Equal => {
+                if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 1 {
+                if self.parameters.len() > 2 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() == LoispDatatype::Nothing {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                    || self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                {
+                    return Err(LoispError::MismatchedTypes(
+                        self.parameters[0].token.clone(),
+                    ));
                 }
 
+                self.push_parameters(ir, context, true)?;
+
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Print,
+                        kind: IrInstructionKind::Equal,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            Plus => {
-                self.push_parameters(ir, context, true)?;
+            NotEqual => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -804,46 +2416,340 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                    || self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
                 {
+                    return Err(LoispError::MismatchedTypes(
+                        self.parameters[0].token.clone(),
+                    ));
+                }
+
+                self.push_parameters(ir, context, true)?;
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::NotEqual,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            If => {
+                if self.parameters.len() < 3 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 3 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
+                // both branches are lowered, so a declaration in one of
+                // them would be visible after the `if` no matter which
+                // branch gets executed
+                for p in &self.parameters[1..] {
+                    if p.is_instruction_return() {
+                        if p.clone().instruction_return.unwrap().kind
+                            == LoispInstructionType::SetVar
+                            || p.clone().instruction_return.unwrap().kind
+                                == LoispInstructionType::Alloc
+                            || p.clone().instruction_return.unwrap().kind
+                                == LoispInstructionType::AllocStr
+                            || p.clone().instruction_return.unwrap().kind
+                                == LoispInstructionType::Array
+                            || p.clone().instruction_return.unwrap().kind
+                                == LoispInstructionType::Macro
+                            || p.clone().instruction_return.unwrap().kind
+                                == LoispInstructionType::DefFun
+                        {
+                            return Err(LoispError::NoDeclarationsInConditionals(p.token.clone()));
+                        }
+                    }
+                }
+
+                push_value(self.parameters[0].clone(), ir, context)?;
+
+                let if_addr = ir.instructions.len() as i64;
+
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Plus,
+                        kind: IrInstructionKind::If,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+
+                push_value(self.parameters[1].clone(), ir, context)?;
+
+                let else_addr = ir.instructions.len() as i64;
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Jump,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+
+                let after_else = ir.instructions.len() as i64;
+                ir.instructions[if_addr as usize].operand =
+                    IrInstructionValue::new().integer(after_else + 1);
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Nop,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Nop,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+
+                push_value(self.parameters[2].clone(), ir, context)?;
+
+                let after_end = ir.instructions.len() as i64;
+                ir.instructions[else_addr as usize].operand =
+                    IrInstructionValue::new().integer(after_end + 1);
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Nop,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Nop,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            Minus => {
-                self.push_parameters(ir, context, true)?;
+            Switch => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 2 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                let branches = switch_branches(self, context)?;
+                let cases: Vec<i64> = branches.iter().filter_map(|(v, _)| *v).collect();
+                let branch_of = |value: Option<i64>| branches.iter().position(|(v, _)| *v == value);
+
+                push_value(self.parameters[0].clone(), ir, context)?;
+
+                // the jumps to the branches, patched once the branches are
+                // lowered, where `None` is the end of the `switch`
+                let mut jumps: Vec<(usize, Option<usize>)> = vec![];
+                let mut push_jump = |target: Option<usize>, ir: &mut IrProgram| {
+                    jumps.push((ir.instructions.len(), target));
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::Jump,
+                            operand: IrInstructionValue::new(),
+                        },
+                        ir,
+                    );
+                };
+
+                if switch_is_dense(&cases) {
+                    let min = *cases.iter().min().unwrap();
+                    let max = *cases.iter().max().unwrap();
+
+                    if min != 0 {
+                        ir_push(
+                            IrInstruction {
+                                kind: IrInstructionKind::PushInteger,
+                                operand: IrInstructionValue::new().integer(min.wrapping_neg()),
+                            },
+                            ir,
+                        );
+                        ir_push(
+                            IrInstruction {
+                                kind: IrInstructionKind::Plus,
+                                operand: IrInstructionValue::new(),
+                            },
+                            ir,
+                        );
+                    }
+
+                    // followed by a jump for each value from `min` to `max`,
+                    // and the one that is taken when the value is out of range
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::JumpTable,
+                            operand: IrInstructionValue::new().integer(max - min + 1),
+                        },
+                        ir,
+                    );
+                    for value in min..=max {
+                        push_jump(branch_of(Some(value)).or(branch_of(None)), ir);
+                    }
+                    push_jump(branch_of(None), ir);
+                } else {
+                    // the value is compared with every case, so it's kept
+                    // in a variable that has no name instead of recomputed
+                    let id = context.variable_count;
+                    context.variable_count += 1;
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::AllocVariable,
+                            operand: IrInstructionValue::new().integer(8),
+                        },
+                        ir,
+                    );
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::PushVariable,
+                            operand: IrInstructionValue::new().integer(id as i64),
+                        },
+                        ir,
+                    );
+                    value_size_as_store_instruction(8, ir);
+
+                    for value in &cases {
+                        ir_push(
+                            IrInstruction {
+                                kind: IrInstructionKind::PushVariable,
+                                operand: IrInstructionValue::new().integer(id as i64),
+                            },
+                            ir,
+                        );
+                        value_size_as_load_instruction(8, ir);
+                        ir_push(
+                            IrInstruction {
+                                kind: IrInstructionKind::PushInteger,
+                                operand: IrInstructionValue::new().integer(*value),
+                            },
+                            ir,
+                        );
+                        ir_push(
+                            IrInstruction {
+                                kind: IrInstructionKind::Equal,
+                                operand: IrInstructionValue::new(),
+                            },
+                            ir,
+                        );
+                        // skips the jump to the branch when it doesn't match
+                        let next = ir.instructions.len() as i64 + 2;
+                        ir_push(
+                            IrInstruction {
+                                kind: IrInstructionKind::If,
+                                operand: IrInstructionValue::new().integer(next),
+                            },
+                            ir,
+                        );
+                        push_jump(branch_of(Some(*value)), ir);
+                    }
+                    push_jump(branch_of(None), ir);
+                }
+
+                // the type of a branch is taken once it's lowered, when
+                // the variables of a block at its end are still known
+                let mut starts = vec![];
+                let mut ends = vec![];
+                let mut types = vec![];
+                for (_, body) in &branches {
+                    starts.push(ir.instructions.len());
+                    push_value(body.clone(), ir, context)?;
+                    types.push(body.datatype(context).unwrap());
+
+                    ends.push(ir.instructions.len());
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::Jump,
+                            operand: IrInstructionValue::new(),
+                        },
+                        ir,
+                    );
+                }
+
+                let typ = switch_branches_type(&types, branch_of(None).is_some());
+                context.switch_types.insert(self.id, typ.clone());
+
+                // when the value of the `switch` can't be used, the branches
+                // that leave one on the stack go through a `Drop` at the end
+                let drop = ir.instructions.len();
+                if typ == LoispDatatype::Nothing
+                    && types.iter().any(|t| *t != LoispDatatype::Nothing)
                 {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::Drop,
+                            operand: IrInstructionValue::new(),
+                        },
+                        ir,
+                    );
+                }
+
+                let end = ir.instructions.len();
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Nop,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+
+                for (jump, target) in jumps {
+                    let addr = target.map(|b| starts[b]).unwrap_or(end);
+                    ir.instructions[jump].operand = IrInstructionValue::new().integer(addr as i64);
+                }
+                for (jump, branch_type) in ends.into_iter().zip(types) {
+                    let addr =
+                        if typ == LoispDatatype::Nothing && branch_type != LoispDatatype::Nothing {
+                            drop
+                        } else {
+                            end
+                        };
+                    ir.instructions[jump].operand = IrInstructionValue::new().integer(addr as i64);
+                }
+            }
+            Block => {
+                let variables: HashSet<String> = context.variables.keys().cloned().collect();
+                let memories: HashSet<String> = context.memories.keys().cloned().collect();
+                let local_variables: HashSet<String> =
+                    context.local_variables.keys().cloned().collect();
+                let local_memories: HashSet<String> =
+                    context.local_memories.keys().cloned().collect();
+
+                // only the value of the last sub-expression is kept
+                // on the stack, the other ones are discarded
+                let mut typ = LoispDatatype::Nothing;
+                for (i, p) in self.parameters.iter().enumerate() {
+                    push_value(p.clone(), ir, context)?;
+
+                    if i + 1 < self.parameters.len() {
+                        drop_unused_value(p.datatype(context).unwrap(), ir);
+                    } else {
+                        typ = p.datatype(context).unwrap();
+                    }
                 }
 
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Minus,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
+                // the variables and memories defined inside of
+                // the block are only visible until its end
+                context.variables.retain(|k, _| variables.contains(k));
+                context.memories.retain(|k, _| memories.contains(k));
+                context
+                    .local_variables
+                    .retain(|k, _| local_variables.contains(k));
+                context
+                    .local_memories
+                    .retain(|k, _| local_memories.contains(k));
+
+                context.block_types.insert(self.id, typ);
             }
-            Multiplication => {
-                self.push_parameters(ir, context, true)?;
+            Less => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -852,22 +2758,23 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
+                self.push_parameters(ir, context, true)?;
+
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Multiplication,
+                        kind: IrInstructionKind::Less,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            Division => {
-                self.push_parameters(ir, context, true)?;
+            Greater => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -876,22 +2783,23 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
+                self.push_parameters(ir, context, true)?;
+
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Division,
+                        kind: IrInstructionKind::Greater,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            Mod => {
-                self.push_parameters(ir, context, true)?;
+            LessEqual => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -900,45 +2808,23 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if !(self.parameters[0].datatype(context).unwrap() == LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() == LoispDatatype::Integer)
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Mod,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
-            }
-            Syscall => {
                 self.push_parameters(ir, context, true)?;
-                if self.parameters.len() > 6 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
-
-                if self.parameters.len() < 2 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
-                }
-
-                for v in &self.parameters {
-                    if v.datatype(context).unwrap() != LoispDatatype::Integer {
-                        return Err(LoispError::MismatchedTypes(self.token.clone()));
-                    }
-                }
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Syscall,
-                        operand: IrInstructionValue::new().integer(self.parameters.len() as i64),
+                        kind: IrInstructionKind::LessEqual,
+                        operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            SetVar => {
+            GreaterEqual => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -947,75 +2833,23 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
-
-                if self.parameters[1].datatype(context).unwrap() == LoispDatatype::Word {
-                    return Err(LoispError::ParserError(ParserError::InvalidSyntax(
-                        self.token.clone(),
-                    )));
-                }
-
-                let variable = LoispVariable {
-                    id: context.variable_count,
-                    value: self.parameters[1].clone(),
-                };
-
-                if let Some(_) = context
-                    .variables
-                    .get(&self.parameters[0].clone().word.unwrap())
-                {
-                    return Err(LoispError::VariableRedefinition(
-                        self.parameters[0].token.clone(),
-                    ));
-                }
-
-                if let Some(_) = context
-                    .local_variables
-                    .get(&self.parameters[0].clone().word.unwrap())
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                    && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
-                    return Err(LoispError::VariableRedefinition(
-                        self.parameters[0].token.clone(),
-                    ));
-                }
-
-                if context.inside_fun {
-                    context.insert_local_variable(
-                        self.parameters[0].clone().word.unwrap(),
-                        variable.clone(),
-                    );
-                } else {
-                    context.insert_variable(
-                        self.parameters[0].clone().word.unwrap(),
-                        variable.clone(),
-                    );
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::AllocVariable,
-                        operand: IrInstructionValue::new()
-                            .integer(variable.clone().value.size(context) as i64),
-                    },
-                    ir,
-                );
-
-                push_value(self.parameters.clone().last().unwrap().clone(), ir, context)?;
+                self.push_parameters(ir, context, true)?;
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::PushVariable,
-                        operand: IrInstructionValue::new().integer(variable.clone().id as i64),
+                        kind: IrInstructionKind::GreaterEqual,
+                        operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
-                value_size_as_store_instruction(
-                    variable.clone().value.datatype(context).unwrap().size(),
-                    ir,
-                );
             }
-            GetVar => {
+            PtrTo => {
                 if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1029,8 +2863,7 @@ impl LoispInstruction {
                 }
 
                 if let Some(var) = context
-                    .variables
-                    .clone()
+                    .local_variables
                     .get(self.parameters[0].word.as_ref().unwrap())
                 {
                     ir_push(
@@ -1040,10 +2873,8 @@ impl LoispInstruction {
                         },
                         ir,
                     );
-                    value_size_as_load_instruction(var.value.clone().size(context), ir);
                 } else if let Some(var) = context
-                    .local_variables
-                    .clone()
+                    .variables
                     .get(self.parameters[0].word.as_ref().unwrap())
                 {
                     ir_push(
@@ -1053,14 +2884,36 @@ impl LoispInstruction {
                         },
                         ir,
                     );
-                    value_size_as_load_instruction(var.value.clone().size(context), ir);
                 } else {
                     return Err(LoispError::VariableNotFound(
                         self.parameters[0].token.clone(),
                     ));
                 }
             }
-            ChVar => {
+            Load64 => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                self.push_parameters(ir, context, true)?;
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Load64,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Store64 => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1069,165 +2922,234 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                if let Some(var) = context
-                    .variables
-                    .get(self.parameters[0].word.as_ref().unwrap())
-                {
-                    let parameter1 = self.parameters[1].clone();
-                    let variable_value = var.value.clone();
-                    if parameter1.datatype(context).unwrap()
-                        != variable_value.datatype(context).unwrap()
-                    {
-                        return Err(LoispError::MismatchedTypes(self.token.clone()));
-                    }
-                } else if let Some(var) = context
-                    .local_variables
-                    .get(self.parameters[0].word.as_ref().unwrap())
-                {
-                    let parameter1 = self.parameters[1].clone();
-                    let variable_value = var.value.clone();
-                    if parameter1.datatype(context).unwrap()
-                        != variable_value.datatype(context).unwrap()
-                    {
-                        return Err(LoispError::MismatchedTypes(self.token.clone()));
-                    }
-                } else {
-                    return Err(LoispError::VariableNotFound(
-                        self.parameters[0].token.clone(),
-                    ));
+                self.push_parameters(ir, context, true)?;
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Store64,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Load32 => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                let var: LoispVariable;
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
 
-                if let Some(v) = context
-                    .variables
-                    .get(self.parameters[0].word.as_ref().unwrap())
-                {
-                    var = v.clone();
-                } else if let Some(v) = context
-                    .local_variables
-                    .get(self.parameters[0].word.as_ref().unwrap())
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                self.push_parameters(ir, context, true)?;
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Load32,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Store32 => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
-                    var = v.clone();
-                } else {
-                    return Err(LoispError::VariableNotFound(
-                        self.parameters[0].token.clone(),
-                    ));
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                self.push_parameters(ir, context, true)?;
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Store32,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+            }
+            Load16 => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                push_value(self.parameters.clone().last().unwrap().clone(), ir, context)?;
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                self.push_parameters(ir, context, true)?;
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::PushVariable,
-                        operand: IrInstructionValue::new().integer(var.id as i64),
+                        kind: IrInstructionKind::Load16,
+                        operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
-                value_size_as_store_instruction(
-                    var.clone().value.datatype(context).unwrap().size(),
-                    ir,
-                );
             }
-            While => {
-                let loop_begin = ir.instructions.len() as i64;
-
+            Store16 => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                push_value(self.parameters[0].clone(), ir, context)?;
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
 
-                let if_addr = ir.instructions.len();
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                self.push_parameters(ir, context, true)?;
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::If,
+                        kind: IrInstructionKind::Store16,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
+            }
+            Load8 => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
 
-                {
-                    let mut parameters = self.parameters.clone();
-                    parameters.remove(0);
-                    for p in parameters {
-                        if p.is_instruction_return() {
-                            if p.clone().instruction_return.unwrap().kind
-                                == LoispInstructionType::SetVar
-                                || p.clone().instruction_return.unwrap().kind
-                                    == LoispInstructionType::Alloc
-                                || p.clone().instruction_return.unwrap().kind
-                                    == LoispInstructionType::Macro
-                                || p.clone().instruction_return.unwrap().kind
-                                    == LoispInstructionType::DefFun
-                            {
-                                return Err(LoispError::NoDeclarationsInLoops(p.token));
-                            }
-                        }
-                        push_value(p, ir, context)?;
-                    }
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
+                self.push_parameters(ir, context, true)?;
+
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Jump,
-                        operand: IrInstructionValue::new().integer(loop_begin),
+                        kind: IrInstructionKind::Load8,
+                        operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
+            }
+            Load64Offset | Load32Offset | Load16Offset | Load8Offset => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
 
-                let after_end = ir.instructions.len() as i64;
-                ir.instructions[if_addr as usize].operand =
-                    IrInstructionValue::new().integer(after_end + 1);
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                let offset = value_as_offset(&self.parameters[1], context)?;
+                push_value(self.parameters[0].clone(), ir, context)?;
 
+                let kind = match self.kind {
+                    Load64Offset => IrInstructionKind::Load64,
+                    Load32Offset => IrInstructionKind::Load32,
+                    Load16Offset => IrInstructionKind::Load16,
+                    _ => IrInstructionKind::Load8,
+                };
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Nop,
-                        operand: IrInstructionValue::new(),
+                        kind,
+                        operand: IrInstructionValue::new().integer(offset),
                     },
                     ir,
                 );
+            }
+            Store64Offset | Store32Offset | Store16Offset | Store8Offset => {
+                if self.parameters.len() < 3 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 3 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
+                    || self.parameters[2].datatype(context).unwrap() != LoispDatatype::Integer
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                let offset = value_as_offset(&self.parameters[1], context)?;
+                // the store pops the pointer first, and then the value
+                push_value(self.parameters[2].clone(), ir, context)?;
+                push_value(self.parameters[0].clone(), ir, context)?;
+
+                let kind = match self.kind {
+                    Store64Offset => IrInstructionKind::Store64,
+                    Store32Offset => IrInstructionKind::Store32,
+                    Store16Offset => IrInstructionKind::Store16,
+                    _ => IrInstructionKind::Store8,
+                };
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Nop,
-                        operand: IrInstructionValue::new(),
+                        kind,
+                        operand: IrInstructionValue::new().integer(offset),
                     },
                     ir,
                 );
             }
-            Equal => {
-                if self.parameters.len() < 2 {
+            Load32Signed | Load16Signed | Load8Signed => {
+                if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 2 {
+                if self.parameters.len() > 1 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    || self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                {
-                    return Err(LoispError::MismatchedTypes(
-                        self.parameters[0].token.clone(),
-                    ));
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
                 self.push_parameters(ir, context, true)?;
 
+                let kind = match self.kind {
+                    Load32Signed => IrInstructionKind::Load32Signed,
+                    Load16Signed => IrInstructionKind::Load16Signed,
+                    _ => IrInstructionKind::Load8Signed,
+                };
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Equal,
+                        kind,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            NotEqual => {
+            Store8 => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1236,104 +3158,249 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    || self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
-                    return Err(LoispError::MismatchedTypes(
-                        self.parameters[0].token.clone(),
-                    ));
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
                 self.push_parameters(ir, context, true)?;
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::NotEqual,
+                        kind: IrInstructionKind::Store8,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            If => {
-                if self.parameters.len() < 3 {
+            Alloc => {
+                if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 3 {
+                if self.parameters.len() > 2 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                push_value(self.parameters[0].clone(), ir, context)?;
+                warn_if_name_is_reserved(&self.parameters[0]);
 
-                let if_addr = ir.instructions.len() as i64;
+                if let Some(_) = context
+                    .local_memories
+                    .get(&self.parameters[0].clone().word.unwrap())
+                {
+                    redefinition(
+                        &self.parameters[0],
+                        context,
+                        LoispError::MemoryRedefinition(self.parameters[0].token.clone()),
+                    )?;
+                }
+
+                let alloc;
+                if self.parameters[1].is_instruction_return() {
+                    alloc = self.parameters[1]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?;
+                } else {
+                    alloc = self.parameters[1].integer.unwrap();
+                }
+
+                // a local memory can shadow a global one
+                if !context.inside_fun
+                    && context
+                        .memories
+                        .contains_key(&self.parameters[0].clone().word.unwrap())
+                {
+                    redefinition(
+                        &self.parameters[0],
+                        context,
+                        LoispError::MemoryRedefinition(self.parameters[0].token.clone()),
+                    )?;
+                }
+
+                let memory = LoispMemory {
+                    id: context.memory_count,
+                    alloc: alloc as usize,
+                };
+
+                if context.inside_fun {
+                    context.insert_local_memory(self.parameters[0].clone().word.unwrap(), memory);
+                } else {
+                    context.insert_memory(self.parameters[0].clone().word.unwrap(), memory);
+                }
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::If,
-                        operand: IrInstructionValue::new(),
+                        kind: IrInstructionKind::AllocMemory,
+                        operand: IrInstructionValue::new().integer(alloc),
                     },
                     ir,
                 );
+            }
+            AllocStr => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
 
-                push_value(self.parameters[1].clone(), ir, context)?;
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                // the bytes are copied when the program starts,
+                // so they must be known at compile time
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word
+                    || self.parameters[1].string.is_none()
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                warn_if_name_is_reserved(&self.parameters[0]);
+
+                let name = self.parameters[0].clone().word.unwrap();
+                if (!context.inside_fun && context.memories.contains_key(&name))
+                    || context.local_memories.contains_key(&name)
+                {
+                    redefinition(
+                        &self.parameters[0],
+                        context,
+                        LoispError::MemoryRedefinition(self.parameters[0].token.clone()),
+                    )?;
+                }
+
+                let string = self.parameters[1].clone().string.unwrap();
+                // with room for the null terminator
+                let alloc = escape_string(string.clone()).len() + 1;
+
+                let memory = LoispMemory {
+                    id: context.memory_count,
+                    alloc,
+                };
+
+                if context.inside_fun {
+                    context.insert_local_memory(name, memory);
+                } else {
+                    context.insert_memory(name, memory);
+                }
 
-                let else_addr = ir.instructions.len() as i64;
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Jump,
-                        operand: IrInstructionValue::new(),
+                        kind: IrInstructionKind::AllocMemory,
+                        operand: IrInstructionValue::new()
+                            .integer(alloc as i64)
+                            .string(string),
                     },
                     ir,
                 );
+            }
+            GetMem => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
 
-                let after_else = ir.instructions.len() as i64;
-                ir.instructions[if_addr as usize].operand =
-                    IrInstructionValue::new().integer(after_else + 1);
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                if let Some(mem) = context
+                    .local_memories
+                    .get(&self.parameters[0].clone().word.unwrap())
+                {
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::PushMemory,
+                            operand: IrInstructionValue::new().integer(mem.id as i64),
+                        },
+                        ir,
+                    );
+                } else if let Some(mem) = context
+                    .memories
+                    .get(&self.parameters[0].clone().word.unwrap())
+                {
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::PushMemory,
+                            operand: IrInstructionValue::new().integer(mem.id as i64),
+                        },
+                        ir,
+                    );
+                } else {
+                    return Err(LoispError::MemoryNotFound(self.parameters[0].token.clone()));
+                }
+            }
+            CastPointer => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
 
+                self.push_parameters(ir, context, true)?;
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Nop,
+                        kind: IrInstructionKind::CastPointer,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
+            }
+            CastInt => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                self.push_parameters(ir, context, true)?;
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Nop,
+                        kind: IrInstructionKind::CastInt,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
+            }
+            ShiftLeft => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
 
-                push_value(self.parameters[2].clone(), ir, context)?;
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
 
-                let after_end = ir.instructions.len() as i64;
-                ir.instructions[else_addr as usize].operand =
-                    IrInstructionValue::new().integer(after_end + 1);
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                self.push_parameters(ir, context, true)?;
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Nop,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Nop,
+                        kind: IrInstructionKind::ShiftLeft,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            Block => {
-                self.push_parameters(ir, context, false)?;
-            }
-            Less => {
+            ShiftRight => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1343,7 +3410,7 @@ impl LoispInstruction {
                 }
 
                 if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
@@ -1352,13 +3419,13 @@ impl LoispInstruction {
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Less,
+                        kind: IrInstructionKind::ShiftRight,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            Greater => {
+            And => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1368,7 +3435,7 @@ impl LoispInstruction {
                 }
 
                 if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
@@ -1377,13 +3444,13 @@ impl LoispInstruction {
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Greater,
+                        kind: IrInstructionKind::And,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            LessEqual => {
+            Or => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1393,7 +3460,7 @@ impl LoispInstruction {
                 }
 
                 if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
@@ -1402,24 +3469,22 @@ impl LoispInstruction {
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::LessEqual,
+                        kind: IrInstructionKind::Or,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            GreaterEqual => {
-                if self.parameters.len() < 2 {
+            Not | LogicalNot => {
+                if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 2 {
+                if self.parameters.len() > 1 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    && self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
-                {
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
@@ -1427,13 +3492,101 @@ impl LoispInstruction {
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::GreaterEqual,
+                        kind: if self.kind == Not {
+                            IrInstructionKind::Not
+                        } else {
+                            IrInstructionKind::LogicalNot
+                        },
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            PtrTo => {
+            Macro => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                warn_if_name_is_reserved(&self.parameters[0]);
+
+                if let Some(_) = context
+                    .macros
+                    .get(&self.parameters[0].clone().word.unwrap())
+                {
+                    redefinition(
+                        &self.parameters[0],
+                        context,
+                        LoispError::MacroRedefinition(self.parameters[0].token.clone()),
+                    )?;
+                }
+
+                let iota = context.iota;
+                let (ops, typ) = lower_macro_body(&self.parameters[1..], context)?;
+
+                for i in &ops.instructions {
+                    if i.kind == IrInstructionKind::Jump {
+                        return Err(LoispError::NoJumpsInMacros(self.token.clone()));
+                    }
+                }
+
+                // every expansion should leave exactly the value that
+                // `expand` says it returns, or nothing at all
+                let effect: Option<i64> = ops.instructions.iter().map(|i| i.stack_effect()).sum();
+                if let Some(effect) = effect {
+                    let expected = if typ != LoispDatatype::Nothing { 1 } else { 0 };
+
+                    if effect != expected {
+                        eprintln!(
+                            "{}: WARNING: The macro `{}` has a stack effect of {}, but {} was expected",
+                            self.parameters[0].token.location,
+                            self.parameters[0].word.as_ref().unwrap(),
+                            effect,
+                            expected
+                        );
+                    }
+                }
+
+                let maccro = LoispMacro {
+                    id: context.macros.len(),
+                    program: ops.clone(),
+                    typ,
+                    body: self.parameters[1..].to_vec(),
+                    iota,
+                };
+
+                context
+                    .macros
+                    .insert(self.parameters[0].clone().word.unwrap(), maccro);
+            }
+            Expand => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                if let Some(mac) = context
+                    .macros
+                    .get(&self.parameters[0].clone().word.unwrap())
+                {
+                    for i in &mac.clone().program.instructions {
+                        ir_push(i.clone(), ir);
+                    }
+                } else {
+                    return Err(LoispError::MacroNotFound(self.parameters[0].token.clone()));
+                }
+            }
+            Pop => {
                 if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1447,8 +3600,8 @@ impl LoispInstruction {
                 }
 
                 if let Some(var) = context
-                    .variables
-                    .get(self.parameters[0].word.as_ref().unwrap())
+                    .local_variables
+                    .get(&self.parameters[0].clone().word.unwrap())
                 {
                     ir_push(
                         IrInstruction {
@@ -1457,9 +3610,10 @@ impl LoispInstruction {
                         },
                         ir,
                     );
+                    value_size_as_store_instruction(var.size, ir);
                 } else if let Some(var) = context
-                    .local_variables
-                    .get(self.parameters[0].word.as_ref().unwrap())
+                    .variables
+                    .get(&self.parameters[0].clone().word.unwrap())
                 {
                     ir_push(
                         IrInstruction {
@@ -1468,13 +3622,14 @@ impl LoispInstruction {
                         },
                         ir,
                     );
+                    value_size_as_store_instruction(var.size, ir);
                 } else {
                     return Err(LoispError::VariableNotFound(
                         self.parameters[0].token.clone(),
                     ));
                 }
             }
-            Load64 => {
+            Include => {
                 if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1483,254 +3638,360 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::String {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                self.push_parameters(ir, context, true)?;
+                if self.parameters[0].is_instruction_return() {
+                    return Err(LoispError::CantEvaluateAtCompileTime(self.token.clone()));
+                }
 
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Load64,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
-            }
-            Store64 => {
-                if self.parameters.len() < 2 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                fn exists(p: &str) -> bool {
+                    return Path::new(p).exists();
                 }
 
-                if self.parameters.len() > 2 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                let mut full_path = String::new();
+                let mut encountered = false;
+                let given_path = self.parameters[0].clone().string.unwrap();
+                let mut tried: Vec<String> = vec![];
+
+                for p in &context.search_paths {
+                    let joined = Path::new(p).join(&given_path);
+                    let curp = normalize_path(format!("{}", joined.display()).as_str());
+                    if exists(curp.as_str()) {
+                        full_path = curp.clone();
+                        encountered = true;
+                        break;
+                    }
+                    if !tried.contains(&curp) {
+                        tried.push(curp);
+                    }
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
-                {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                if !encountered {
+                    if !exists(given_path.as_str()) {
+                        let given = normalize_path(given_path.as_str());
+                        if !tried.contains(&given) {
+                            tried.push(given);
+                        }
+                        return Err(LoispError::IncludeNotFound(
+                            self.parameters[0].token.clone(),
+                            tried,
+                        ));
+                    }
+                    full_path = normalize_path(given_path.as_str());
                 }
 
-                self.push_parameters(ir, context, true)?;
+                // the file is cached by its canonical path, so including it
+                // through a different spelling doesn't compile it twice
 
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Store64,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
+                if context
+                    .once_files
+                    .contains(&canonical_path(full_path.as_str()))
+                {
+                    return Ok(());
+                }
+
+                compile_file_into_existing_ir(full_path, ir, context)?;
             }
-            Load32 => {
+            DefFun => {
                 if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 1 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                warn_if_name_is_reserved(&self.parameters[0]);
+
+                if let Some(_) = context
+                    .functions
+                    .get(&self.parameters[0].clone().word.unwrap())
+                {
+                    redefinition(
+                        &self.parameters[0],
+                        context,
+                        LoispError::FunctionRedefinition(self.parameters[0].token.clone()),
+                    )?;
                 }
 
-                self.push_parameters(ir, context, true)?;
+                let defun_addr = ir.instructions.len() as i64;
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Load32,
+                        kind: IrInstructionKind::Jump,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
-            }
-            Store32 => {
-                if self.parameters.len() < 2 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
-                }
 
-                if self.parameters.len() > 2 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                let function_addr = ir.instructions.len() as i64;
+                let name = self.parameters[0].clone().word.unwrap();
+                let mut body = self.parameters.clone();
+                body.remove(0);
+
+                // with a declared return type the function can be called
+                // (recursively) from its own body with a known type
+                let declared_type = match body.first() {
+                    Some(p) => value_as_declared_return_type(p, context)?,
+                    None => None,
+                };
+                if let Some(typ) = &declared_type {
+                    body.remove(0);
+                    context.functions.insert(
+                        name.clone(),
+                        LoispFunction {
+                            addr: function_addr as usize,
+                            typ: typ.clone(),
+                        },
+                    );
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                let mut function_type = LoispDatatype::Nothing;
+                let mut last_token = self.parameters[0].token.clone();
+                let previous_inside_func_state = context.inside_fun;
+                context.inside_fun = true;
+                context.returns = vec![];
                 {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
-
-                self.push_parameters(ir, context, true)?;
+                    let params = body;
+                    let params_count = params.len();
+                    for (i, p) in params.into_iter().enumerate() {
+                        if p.is_instruction_return() {
+                            if p.clone().instruction_return.unwrap().kind
+                                == LoispInstructionType::DefFun
+                                || p.clone().instruction_return.unwrap().kind
+                                    == LoispInstructionType::Macro
+                            {
+                                return Err(LoispError::NoDeclarationsInFunctions(p.token.clone()));
+                            }
+                        }
+                        push_value(p.clone(), ir, context)?;
+                        last_token = p.token.clone();
+
+                        // the value of the last instruction is the return
+                        // value of the function, the other ones are discarded
+                        if i + 1 < params_count {
+                            drop_unused_value(p.datatype(context).unwrap(), ir);
+                        } else if declared_type == Some(LoispDatatype::Nothing)
+                            && p.instruction_return.as_ref().map(|i| i.kind.clone())
+                                != Some(LoispInstructionType::Return)
+                        {
+                            // a function declared to return nothing discards the
+                            // value of its last instruction instead of returning it
+                            drop_unused_value(p.datatype(context).unwrap(), ir);
+                        } else if let Some(LoispInstructionType::Return) =
+                            p.instruction_return.as_ref().map(|i| i.kind.clone())
+                        {
+                            function_type = context.returns.last().unwrap().2.clone();
+                        } else {
+                            function_type = p.datatype(context).unwrap();
+                        }
 
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Store32,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
-            }
-            Load16 => {
-                if self.parameters.len() < 1 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                        // a call right before the end of the function can jump to the
+                        // other function, which then returns straight to our caller,
+                        // so tail recursion doesn't grow the return stack
+                        if i + 1 == params_count {
+                            let len = ir.instructions.len();
+                            if len >= 2
+                                && ir.instructions[len - 2].kind == IrInstructionKind::Call
+                                && ir.instructions[len - 1].kind == IrInstructionKind::Nop
+                                && p.instruction_return.as_ref().map(|i| i.kind.clone())
+                                    == Some(LoispInstructionType::Call)
+                            {
+                                ir.instructions[len - 2].kind = IrInstructionKind::Jump;
+                            }
+                        }
+                    }
                 }
 
-                if self.parameters.len() > 1 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                if let Some(typ) = &declared_type {
+                    if *typ != function_type {
+                        return Err(LoispError::ReturnTypeMismatch(
+                            last_token,
+                            name,
+                            typ.clone(),
+                            function_type,
+                        ));
+                    }
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                // every `return` jumps to the end of the function, and
+                // needs to leave the same type as the function's body
+                let returns = std::mem::take(&mut context.returns);
+                for (addr, token, typ) in returns {
+                    if typ != function_type {
+                        if let Some(declared) = declared_type {
+                            return Err(LoispError::ReturnTypeMismatch(token, name, declared, typ));
+                        }
+                        return Err(LoispError::MismatchedTypes(token));
+                    }
+
+                    ir.instructions[addr].operand =
+                        IrInstructionValue::new().integer(ir.instructions.len() as i64);
                 }
 
-                self.push_parameters(ir, context, true)?;
+                context.local_memories = HashMap::new();
+                context.local_variables = HashMap::new();
+                context.inside_fun = previous_inside_func_state;
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Load16,
+                        kind: IrInstructionKind::Return,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
-            }
-            Store16 => {
-                if self.parameters.len() < 2 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
-                }
-
-                if self.parameters.len() > 2 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
-
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
-                {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
 
-                self.push_parameters(ir, context, true)?;
+                let after_end = ir.instructions.len() as i64;
+                ir.instructions[defun_addr as usize].operand =
+                    IrInstructionValue::new().integer(after_end + 1);
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Store16,
+                        kind: IrInstructionKind::Nop,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
-            }
-            Load8 => {
-                if self.parameters.len() < 1 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
-                }
-
-                if self.parameters.len() > 1 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
-
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
-
-                self.push_parameters(ir, context, true)?;
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Load8,
+                        kind: IrInstructionKind::Nop,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
+
+                let function = LoispFunction {
+                    addr: function_addr as usize,
+                    typ: function_type,
+                };
+
+                context.functions.insert(name, function);
             }
-            Store8 => {
-                if self.parameters.len() < 2 {
+            Call => {
+                if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 2 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                    let mut params = self.parameters.clone();
+                    params.remove(0);
+                    for p in params.iter().rev() {
+                        // something that returns nothing can't be passed
+                        // to the function, it wouldn't push any value
+                        if p.datatype(context).unwrap() == LoispDatatype::Nothing {
+                            return Err(LoispError::MismatchedTypes(self.token.clone()));
+                        }
+                        push_value(p.clone(), ir, context)?;
+                    }
                 }
 
-                self.push_parameters(ir, context, true)?;
-
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Store8,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
+                if let Some(f) = context
+                    .functions
+                    .get(&self.parameters[0].clone().word.unwrap())
+                {
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::Call,
+                            operand: IrInstructionValue::new().integer(f.addr as i64),
+                        },
+                        ir,
+                    );
+                    ir_push(
+                        IrInstruction {
+                            kind: IrInstructionKind::Nop,
+                            operand: IrInstructionValue::new().integer(f.addr as i64),
+                        },
+                        ir,
+                    );
+                } else {
+                    return Err(LoispError::FunctionNotFound(
+                        self.parameters[0].token.clone(),
+                    ));
+                }
             }
-            Alloc => {
+            Increment
+                if self
+                    .parameters
+                    .first()
+                    .and_then(|p| p.word.as_ref())
+                    .is_some() =>
+            {
+                push_variable_step(self, IrInstructionKind::Plus, ir, context)?;
+            }
+            Decrement => {
+                push_variable_step(self, IrInstructionKind::Minus, ir, context)?;
+            }
+            PlusAssign | MinusAssign | MultiplicationAssign | DivisionAssign => {
+                // unlike `increment` and `decrement`, the value is required
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 2 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                let operation = match self.kind {
+                    PlusAssign => IrInstructionKind::Plus,
+                    MinusAssign => IrInstructionKind::Minus,
+                    MultiplicationAssign => IrInstructionKind::Multiplication,
+                    _ => IrInstructionKind::Division,
+                };
+                push_variable_step(self, operation, ir, context)?;
+            }
+            Increment => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
-                {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if let Some(_) = context
-                    .local_memories
-                    .get(&self.parameters[0].clone().word.unwrap())
-                {
-                    return Err(LoispError::MemoryRedefinition(
-                        self.parameters[0].token.clone(),
-                    ));
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                let alloc;
-                if self.parameters[1].is_instruction_return() {
-                    alloc = self.parameters[1]
+                let increment;
+                if self.parameters[0].is_instruction_return() {
+                    increment = self.parameters[0]
                         .clone()
                         .instruction_return
                         .unwrap()
                         .evaluate_at_compile_time(context)?;
                 } else {
-                    alloc = self.parameters[1].integer.unwrap();
+                    increment = self.parameters[0].integer.unwrap();
                 }
 
-                if let Some(_) = context
-                    .memories
-                    .get(&self.parameters[0].clone().word.unwrap())
-                {
-                    return Err(LoispError::MemoryRedefinition(
-                        self.parameters[0].token.clone(),
-                    ));
-                } else {
-                    let memory = LoispMemory {
-                        id: context.memory_count,
-                        alloc: alloc as usize,
-                    };
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::PushInteger,
+                        operand: IrInstructionValue::new().integer(context.iota),
+                    },
+                    ir,
+                );
 
-                    if context.inside_fun {
-                        context
-                            .insert_local_memory(self.parameters[0].clone().word.unwrap(), memory);
-                    } else {
-                        context.insert_memory(self.parameters[0].clone().word.unwrap(), memory);
-                    }
+                context.iota += increment;
+            }
+            Reset => {
+                if self.parameters.len() != 0 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::AllocMemory,
-                        operand: IrInstructionValue::new().integer(alloc),
+                        kind: IrInstructionKind::PushInteger,
+                        operand: IrInstructionValue::new().integer(context.iota),
                     },
                     ir,
                 );
+
+                context.iota = 0;
             }
-            GetMem => {
+            TypeOf => {
                 if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1739,98 +4000,69 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
-
-                if let Some(mem) = context
-                    .memories
-                    .get(&self.parameters[0].clone().word.unwrap())
-                {
-                    ir_push(
-                        IrInstruction {
-                            kind: IrInstructionKind::PushMemory,
-                            operand: IrInstructionValue::new().integer(mem.id as i64),
-                        },
-                        ir,
-                    );
-                } else if let Some(mem) = context
-                    .local_memories
-                    .get(&self.parameters[0].clone().word.unwrap())
+                let datatype;
                 {
-                    ir_push(
-                        IrInstruction {
-                            kind: IrInstructionKind::PushMemory,
-                            operand: IrInstructionValue::new().integer(mem.id as i64),
-                        },
-                        ir,
-                    );
-                } else {
-                    return Err(LoispError::MemoryNotFound(self.parameters[0].token.clone()));
-                }
-            }
-            CastPointer => {
-                if self.parameters.len() < 1 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                    // lower the parameter into copies, so declarations inside
+                    // it don't take ids that never get allocated
+                    let mut newir = ir.clone();
+                    let mut newcontext = context.clone();
+                    self.push_parameters(&mut newir, &mut newcontext, true)?;
+
+                    if let Some(last) = newir.instructions.last() {
+                        datatype = last.get_loisp_datatype();
+                    } else {
+                        datatype = LoispDatatype::Nothing;
+                    }
                 }
 
-                if self.parameters.len() > 1 {
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::PushString,
+                        operand: IrInstructionValue::new().string(format!("{:?}", datatype)),
+                    },
+                    ir,
+                );
+            }
+            Argc => {
+                if self.parameters.len() != 0 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                self.push_parameters(ir, context, true)?;
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::CastPointer,
+                        kind: IrInstructionKind::Argc,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            CastInt => {
-                if self.parameters.len() < 1 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
-                }
-
-                if self.parameters.len() > 1 {
+            Argv => {
+                if self.parameters.len() != 0 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                self.push_parameters(ir, context, true)?;
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::CastInt,
+                        kind: IrInstructionKind::Argv,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            ShiftLeft => {
-                if self.parameters.len() < 2 {
-                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
-                }
-
-                if self.parameters.len() > 2 {
+            Envp => {
+                if self.parameters.len() != 0 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
-
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
-                {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
-
-                self.push_parameters(ir, context, true)?;
+                }
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::ShiftLeft,
+                        kind: IrInstructionKind::Envp,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            ShiftRight => {
+            Load => {
                 if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -1839,49 +4071,60 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
-                {
+                let width = value_as_width(&self.parameters[0], context)?;
+
+                if self.parameters[1].datatype(context).unwrap() != LoispDatatype::Pointer {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                self.push_parameters(ir, context, true)?;
-
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::ShiftRight,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
+                push_value(self.parameters[1].clone(), ir, context)?;
+                value_size_as_load_instruction(width, ir);
             }
-            And => {
-                if self.parameters.len() < 2 {
+            Store => {
+                if self.parameters.len() < 3 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 2 {
+                if self.parameters.len() > 3 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
+                let width = value_as_width(&self.parameters[0], context)?;
+
+                if self.parameters[1].datatype(context).unwrap() != LoispDatatype::Pointer
+                    || self.parameters[2].datatype(context).unwrap() != LoispDatatype::Integer
                 {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                self.push_parameters(ir, context, true)?;
+                push_value(self.parameters[2].clone(), ir, context)?;
+                push_value(self.parameters[1].clone(), ir, context)?;
+                value_size_as_store_instruction(width, ir);
+            }
+            Pragma => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
 
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::And,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                match self.parameters[0].word.as_ref().unwrap().as_str() {
+                    "once" => {
+                        context
+                            .once_files
+                            .insert(canonical_path(self.token.location.f.as_str()));
+                    }
+                    _ => return Err(LoispError::UnknownPragma(self.parameters[0].token.clone())),
+                }
             }
-            Or => {
-                if self.parameters.len() < 2 {
+            FOpen => {
+                if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
@@ -1889,356 +4132,395 @@ impl LoispInstruction {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
-                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
-                {
+                let path_type = self.parameters[0].datatype(context).unwrap();
+                if path_type != LoispDatatype::String && path_type != LoispDatatype::Pointer {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                self.push_parameters(ir, context, true)?;
+                let flags = if self.parameters.len() > 1 {
+                    file_mode_as_flags(&self.parameters[1], context)?
+                } else {
+                    0
+                };
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Or,
-                        operand: IrInstructionValue::new(),
+                        kind: IrInstructionKind::PushInteger,
+                        operand: IrInstructionValue::new().integer(420),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::PushInteger,
+                        operand: IrInstructionValue::new().integer(flags),
+                    },
+                    ir,
+                );
+                push_value(self.parameters[0].clone(), ir, context)?;
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::PushInteger,
+                        operand: IrInstructionValue::new().integer(-100),
                     },
                     ir,
                 );
+                push_syscall(257, 4, ir);
             }
-            Not => {
-                if self.parameters.len() < 1 {
+            FRead | FWrite => {
+                if self.parameters.len() < 3 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 1 {
+                if self.parameters.len() > 3 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
+                let buffer_type = self.parameters[1].datatype(context).unwrap();
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer
+                    || (buffer_type != LoispDatatype::Pointer
+                        && !(self.kind == FWrite && buffer_type == LoispDatatype::String))
+                    || self.parameters[2].datatype(context).unwrap() != LoispDatatype::Integer
+                {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
                 self.push_parameters(ir, context, true)?;
-
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Not,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
+                push_syscall(if self.kind == FRead { 0 } else { 1 }, 3, ir);
             }
-            Macro => {
-                if self.parameters.len() < 1 {
+            Array => {
+                if self.parameters.len() < 3 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                if self.parameters.len() > 3 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if let Some(_) = context
-                    .macros
-                    .get(&self.parameters[0].clone().word.unwrap())
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word
+                    || self.parameters[2].datatype(context).unwrap() != LoispDatatype::Integer
                 {
-                    return Err(LoispError::MacroRedefinition(
-                        self.parameters[0].token.clone(),
-                    ));
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                let mut ops = IrProgram::new();
+                warn_if_name_is_reserved(&self.parameters[0]);
+
+                let name = self.parameters[0].clone().word.unwrap();
+                if (!context.inside_fun && context.memories.contains_key(&name))
+                    || context.local_memories.contains_key(&name)
                 {
-                    let mut params = self.parameters.clone();
-                    params.remove(0);
-                    for p in params {
-                        if p.is_instruction_return() {
-                            if p.instruction_return.clone().unwrap().kind
-                                == LoispInstructionType::SetVar
-                                || p.clone().instruction_return.unwrap().kind
-                                    == LoispInstructionType::Alloc
-                                || p.clone().instruction_return.unwrap().kind
-                                    == LoispInstructionType::Macro
-                                || p.clone().instruction_return.unwrap().kind
-                                    == LoispInstructionType::DefFun
-                            {
-                                return Err(LoispError::NoDeclarationsInMacros(p.token));
-                            }
-                        }
-                        push_value(p.clone(), &mut ops, context)?;
-                    }
+                    redefinition(
+                        &self.parameters[0],
+                        context,
+                        LoispError::MemoryRedefinition(self.parameters[0].token.clone()),
+                    )?;
                 }
 
-                for i in &ops.instructions {
-                    if i.kind == IrInstructionKind::Jump {
-                        return Err(LoispError::NoJumpsInMacros(self.token.clone()));
-                    }
+                let elemsize = value_as_width(&self.parameters[1], context)?;
+                let count;
+                if self.parameters[2].is_instruction_return() {
+                    count = self.parameters[2]
+                        .clone()
+                        .instruction_return
+                        .unwrap()
+                        .evaluate_at_compile_time(context)?;
+                } else {
+                    count = self.parameters[2].integer.unwrap();
                 }
 
-                let maccro = LoispMacro {
-                    id: context.macros.len(),
-                    program: ops.clone(),
+                // the size goes in the operand of the IR, so it has to fit in an i64
+                let alloc = match (elemsize as i64).checked_mul(count) {
+                    Some(alloc) if count > 0 => alloc as usize,
+                    _ => {
+                        return Err(LoispError::InvalidArrayLength(
+                            self.parameters[2].token.clone(),
+                        ))
+                    }
                 };
 
-                context
-                    .macros
-                    .insert(self.parameters[0].clone().word.unwrap(), maccro);
+                let memory = LoispMemory {
+                    id: context.memory_count,
+                    alloc,
+                };
+                let array = LoispArray {
+                    memory: memory.id,
+                    index: memory.id + 1,
+                    elemsize,
+                    count: count as usize,
+                };
+
+                if context.inside_fun {
+                    context.insert_local_memory(name.clone(), memory.clone());
+                } else {
+                    context.insert_memory(name.clone(), memory.clone());
+                }
+                context.arrays.insert(name, array);
+
+                // the memory of the array is followed by an unnamed
+                // one that holds the index of the element being accessed
+                context.memory_count += 1;
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::AllocMemory,
+                        operand: IrInstructionValue::new().integer(memory.alloc as i64),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::AllocMemory,
+                        operand: IrInstructionValue::new().integer(8),
+                    },
+                    ir,
+                );
             }
-            Expand => {
-                if self.parameters.len() < 1 {
+            ArrayGet => {
+                if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 1 {
+                if self.parameters.len() > 2 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
-                }
-
-                if let Some(mac) = context
-                    .macros
-                    .get(&self.parameters[0].clone().word.unwrap())
-                {
-                    for i in &mac.clone().program.instructions {
-                        ir_push(i.clone(), ir);
-                    }
-                } else {
-                    return Err(LoispError::MacroNotFound(self.parameters[0].token.clone()));
-                }
+                let array = push_array_element_address(
+                    &self.parameters[0],
+                    &self.parameters[1],
+                    &self.token,
+                    ir,
+                    context,
+                )?;
+                value_size_as_load_instruction(array.elemsize, ir);
             }
-            Pop => {
-                if self.parameters.len() < 1 {
+            ArraySet => {
+                if self.parameters.len() < 3 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 1 {
+                if self.parameters.len() > 3 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
+                if self.parameters[2].datatype(context).unwrap() != LoispDatatype::Integer {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                if let Some(var) = context
-                    .variables
-                    .get(&self.parameters[0].clone().word.unwrap())
-                {
-                    ir_push(
-                        IrInstruction {
-                            kind: IrInstructionKind::PushVariable,
-                            operand: IrInstructionValue::new().integer(var.id as i64),
-                        },
-                        ir,
-                    );
-                    ir_push(
-                        IrInstruction {
-                            kind: IrInstructionKind::Store64,
-                            operand: IrInstructionValue::new(),
-                        },
-                        ir,
-                    );
-                } else if let Some(var) = context
-                    .local_variables
-                    .get(&self.parameters[0].clone().word.unwrap())
-                {
-                    ir_push(
-                        IrInstruction {
-                            kind: IrInstructionKind::PushVariable,
-                            operand: IrInstructionValue::new().integer(var.id as i64),
-                        },
-                        ir,
-                    );
-                    ir_push(
-                        IrInstruction {
-                            kind: IrInstructionKind::Store64,
-                            operand: IrInstructionValue::new(),
-                        },
-                        ir,
-                    );
-                } else {
-                    return Err(LoispError::VariableNotFound(
-                        self.parameters[0].token.clone(),
-                    ));
-                }
+                // the value is pushed first, so an `aget` of the same
+                // array inside of it can't change the index
+                push_value(self.parameters[2].clone(), ir, context)?;
+
+                let array = push_array_element_address(
+                    &self.parameters[0],
+                    &self.parameters[1],
+                    &self.token,
+                    ir,
+                    context,
+                )?;
+                value_size_as_store_instruction(array.elemsize, ir);
             }
-            Include => {
+            Struct => {
                 if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 1 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
-
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::String {
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                if self.parameters[0].is_instruction_return() {
-                    return Err(LoispError::CantEvaluateAtCompileTime(self.token.clone()));
-                }
+                warn_if_name_is_reserved(&self.parameters[0]);
 
-                fn exists(p: &str) -> bool {
-                    return Path::new(p).exists();
+                let name = self.parameters[0].clone().word.unwrap();
+                if context.structs.contains_key(&name) {
+                    return Err(LoispError::StructRedefinition(
+                        self.parameters[0].token.clone(),
+                    ));
                 }
 
-                let mut full_path = String::new();
-                let mut encountered = false;
-                let given_path = self.parameters[0].clone().string.unwrap();
+                // the fields are laid out one after the other,
+                // in the order they were declared
+                let mut structure = LoispStruct {
+                    fields: vec![],
+                    size: 0,
+                };
+                for p in &self.parameters[1..] {
+                    let field = match &p.instruction_return {
+                        Some(i) if i.kind == LoispInstructionType::StructField => i,
+                        _ => return Err(LoispError::MismatchedTypes(self.token.clone())),
+                    };
 
-                for p in DEFAULT_SEARCH_PATHS {
-                    let curp = format!("{}/{}", p, given_path);
-                    if exists(curp.as_str()) {
-                        full_path = curp.clone();
-                        encountered = true;
-                        break;
+                    if field.parameters.len() < 1 {
+                        return Err(LoispError::NotEnoughParameters(field.token.clone()));
                     }
-                }
 
-                if !encountered {
-                    full_path = given_path;
-                }
+                    if field.parameters.len() > 1 {
+                        return Err(LoispError::TooMuchParameters(field.token.clone()));
+                    }
 
-                full_path = full_path.replace("//", "/").to_string();
+                    let typ = value_as_datatype(&field.parameters[0], &field.token, context)?;
+                    if typ == LoispDatatype::Nothing {
+                        return Err(LoispError::MismatchedTypes(field.token.clone()));
+                    }
 
-                compile_file_into_existing_ir(full_path, ir, context)?;
+                    let field_name = field.token.value.string.clone();
+                    if structure.fields.iter().any(|f| f.name == field_name) {
+                        return Err(LoispError::FieldRedefinition(field.token.clone()));
+                    }
+
+                    structure.fields.push(LoispField {
+                        name: field_name,
+                        offset: structure.size,
+                    });
+                    structure.size += typ.size();
+                }
+
+                context.structs.insert(name, structure);
             }
-            DefFun => {
-                if self.parameters.len() < 1 {
+            // the parser only makes fields inside of a `struct`, which reads
+            // them itself, but a field on its own is still not an instruction
+            StructField => {
+                return Err(LoispError::ParserError(ParserError::InvalidSyntax(
+                    self.token.clone(),
+                )))
+            }
+            Field => {
+                if self.parameters.len() < 3 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                if self.parameters.len() > 3 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                if let Some(_) = context
-                    .functions
-                    .get(&self.parameters[0].clone().word.unwrap())
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Pointer
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Word
+                    || self.parameters[2].datatype(context).unwrap() != LoispDatatype::Word
                 {
-                    return Err(LoispError::FunctionRedefinition(
-                        self.parameters[0].token.clone(),
-                    ));
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                let defun_addr = ir.instructions.len() as i64;
-
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Jump,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
-
-                let function_addr = ir.instructions.len() as i64;
-                let mut function_type = LoispDatatype::Nothing;
-                let previous_inside_func_state = context.inside_fun;
-                context.inside_fun = true;
+                let structure = match context
+                    .structs
+                    .get(self.parameters[1].word.as_ref().unwrap())
                 {
-                    let mut params = self.parameters.clone();
-                    params.remove(0);
-                    for p in params {
-                        if p.is_instruction_return() {
-                            if p.clone().instruction_return.unwrap().kind
-                                == LoispInstructionType::DefFun
-                                || p.clone().instruction_return.unwrap().kind
-                                    == LoispInstructionType::Macro
-                            {
-                                return Err(LoispError::NoDeclarationsInFunctions(p.token.clone()));
-                            }
-                        }
-                        push_value(p.clone(), ir, context)?;
+                    Some(s) => s,
+                    None => {
+                        return Err(LoispError::StructNotFound(self.parameters[1].token.clone()))
                     }
+                };
 
-                    if let Some(i) = ir.instructions.last() {
-                        function_type = i.get_loisp_datatype();
+                let offset = match structure
+                    .fields
+                    .iter()
+                    .find(|f| Some(&f.name) == self.parameters[2].word.as_ref())
+                {
+                    Some(f) => f.offset,
+                    None => {
+                        return Err(LoispError::FieldNotFound(self.parameters[2].token.clone()))
                     }
-                }
-                context.local_memories = HashMap::new();
-                context.local_variables = HashMap::new();
-                context.inside_fun = previous_inside_func_state;
+                };
 
+                push_value(self.parameters[0].clone(), ir, context)?;
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Return,
-                        operand: IrInstructionValue::new(),
+                        kind: IrInstructionKind::PushInteger,
+                        operand: IrInstructionValue::new().integer(offset as i64),
                     },
                     ir,
                 );
-
-                let after_end = ir.instructions.len() as i64;
-                ir.instructions[defun_addr as usize].operand =
-                    IrInstructionValue::new().integer(after_end + 1);
-
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Nop,
+                        kind: IrInstructionKind::Plus,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
-
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Nop,
+                        kind: IrInstructionKind::CastPointer,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
+            }
+            Returns => {
+                return Err(LoispError::ReturnsOutsideFunctionDeclaration(
+                    self.token.clone(),
+                ));
+            }
+            Case | Default => {
+                return Err(LoispError::CaseOutsideSwitch(self.token.clone()));
+            }
+            Return => {
+                if !context.inside_fun {
+                    return Err(LoispError::ReturnOutsideFunction(self.token.clone()));
+                }
 
-                let function = LoispFunction {
-                    addr: function_addr as usize,
-                    typ: function_type,
-                };
+                if self.parameters.len() > 1 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+                }
+
+                let mut typ = LoispDatatype::Nothing;
+                if let Some(p) = self.parameters.first() {
+                    push_value(p.clone(), ir, context)?;
+                    typ = p.datatype(context).unwrap();
+
+                    if typ == LoispDatatype::Nothing {
+                        return Err(LoispError::MismatchedTypes(self.token.clone()));
+                    }
+                }
 
                 context
-                    .functions
-                    .insert(self.parameters[0].clone().word.unwrap(), function);
+                    .returns
+                    .push((ir.instructions.len(), self.token.clone(), typ));
+
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Jump,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
             }
-            Call => {
-                if self.parameters.len() < 1 {
+            Assert => {
+                if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Word {
-                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                if self.parameters.len() > 2 {
+                    return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                {
-                    let mut params = self.parameters.clone();
-                    params.remove(0);
-                    for p in params.iter().rev() {
-                        push_value(p.clone(), ir, context)?;
-                    }
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                if let Some(f) = context
-                    .functions
-                    .get(&self.parameters[0].clone().word.unwrap())
-                {
-                    ir_push(
-                        IrInstruction {
-                            kind: IrInstructionKind::Call,
-                            operand: IrInstructionValue::new().integer(f.addr as i64),
-                        },
-                        ir,
-                    );
-                    ir_push(
-                        IrInstruction {
-                            kind: IrInstructionKind::Nop,
-                            operand: IrInstructionValue::new().integer(f.addr as i64),
-                        },
-                        ir,
-                    );
+                let message;
+                if let Some(m) = value_as_constant_string(&self.parameters[1], context) {
+                    message = m;
                 } else {
-                    return Err(LoispError::FunctionNotFound(
-                        self.parameters[0].token.clone(),
-                    ));
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                let constant = match &self.parameters[0].instruction_return {
+                    Some(i) => i.evaluate_at_compile_time(context).ok(),
+                    None => self.parameters[0].integer,
+                };
+
+                match constant {
+                    Some(0) => {
+                        return Err(LoispError::AssertionFailed(self.token.clone(), message));
+                    }
+                    Some(_) => {}
+                    None => {
+                        let message =
+                            format!("{}: Assertion failed: {}\\n", self.token.location, message);
+                        push_value(self.parameters[0].clone(), ir, context)?;
+                        push_runtime_check(message, ir);
+                    }
                 }
             }
-            Increment => {
+            ArgAt => {
                 if self.parameters.len() < 1 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
@@ -2251,112 +4533,180 @@ impl LoispInstruction {
                     return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
-                let increment;
-                if self.parameters[0].is_instruction_return() {
-                    increment = self.parameters[0]
-                        .clone()
-                        .instruction_return
-                        .unwrap()
-                        .evaluate_at_compile_time(context)?;
-                } else {
-                    increment = self.parameters[0].integer.unwrap();
-                }
-
+                // argv[i] is at argv + i * 8
+                push_value(self.parameters[0].clone(), ir, context)?;
                 ir_push(
                     IrInstruction {
                         kind: IrInstructionKind::PushInteger,
-                        operand: IrInstructionValue::new().integer(context.iota),
+                        operand: IrInstructionValue::new().integer(8),
                     },
                     ir,
                 );
-
-                context.iota += increment;
-            }
-            Reset => {
-                if self.parameters.len() != 0 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
-
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::PushInteger,
-                        operand: IrInstructionValue::new().integer(context.iota),
+                        kind: IrInstructionKind::Multiplication,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Argv,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Plus,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::Load64,
+                        operand: IrInstructionValue::new(),
+                    },
+                    ir,
+                );
+                ir_push(
+                    IrInstruction {
+                        kind: IrInstructionKind::CastPointer,
+                        operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
-
-                context.iota = 0;
             }
-            TypeOf => {
-                if self.parameters.len() < 1 {
+            StrByte => {
+                if self.parameters.len() < 2 {
                     return Err(LoispError::NotEnoughParameters(self.token.clone()));
                 }
 
-                if self.parameters.len() > 1 {
+                if self.parameters.len() > 2 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                let datatype;
+                let string_type = self.parameters[0].datatype(context).unwrap();
+                if (string_type != LoispDatatype::Pointer && string_type != LoispDatatype::String)
+                    || self.parameters[1].datatype(context).unwrap() != LoispDatatype::Integer
                 {
-                    let mut newir = ir.clone();
-                    self.push_parameters(&mut newir, context, true)?;
-
-                    if let Some(last) = newir.instructions.last() {
-                        datatype = last.get_loisp_datatype();
-                    } else {
-                        datatype = LoispDatatype::Nothing;
-                    }
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
                 }
 
+                self.push_parameters(ir, context, true)?;
+
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::PushString,
-                        operand: IrInstructionValue::new().string(format!("{:?}", datatype)),
+                        kind: IrInstructionKind::Plus,
+                        operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
-            }
-            Argc => {
-                if self.parameters.len() != 0 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
-                }
-
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Argc,
+                        kind: IrInstructionKind::Load8,
                         operand: IrInstructionValue::new(),
                     },
                     ir,
                 );
             }
-            Argv => {
-                if self.parameters.len() != 0 {
-                    return Err(LoispError::TooMuchParameters(self.token.clone()));
+            StrCat => {
+                if self.parameters.len() < 2 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                let mut string = String::new();
+                for p in &self.parameters {
+                    if let Some(s) = value_as_constant_string(p, context) {
+                        string.push_str(s.as_str());
+                    } else {
+                        return Err(LoispError::CantEvaluateAtCompileTime(self.token.clone()));
+                    }
                 }
 
                 ir_push(
                     IrInstruction {
-                        kind: IrInstructionKind::Argv,
-                        operand: IrInstructionValue::new(),
+                        kind: IrInstructionKind::PushString,
+                        operand: IrInstructionValue::new().string(string),
                     },
                     ir,
                 );
             }
-            Envp => {
-                if self.parameters.len() != 0 {
+            FClose => {
+                if self.parameters.len() < 1 {
+                    return Err(LoispError::NotEnoughParameters(self.token.clone()));
+                }
+
+                if self.parameters.len() > 1 {
                     return Err(LoispError::TooMuchParameters(self.token.clone()));
                 }
 
-                ir_push(
-                    IrInstruction {
-                        kind: IrInstructionKind::Envp,
-                        operand: IrInstructionValue::new(),
-                    },
-                    ir,
-                );
+                if self.parameters[0].datatype(context).unwrap() != LoispDatatype::Integer {
+                    return Err(LoispError::MismatchedTypes(self.token.clone()));
+                }
+
+                push_value(self.parameters[0].clone(), ir, context)?;
+                push_syscall(3, 1, ir);
             }
             Nop => {}
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn struct_field_on_its_own_is_an_error() {
+        let instructions = try_parse("(struct point (x int))", "test.loisp".to_string()).unwrap();
+        let field = instructions[0].parameters[1]
+            .instruction_return
+            .clone()
+            .unwrap();
+        assert_eq!(field.kind, LoispInstructionType::StructField);
+
+        let result = field.to_ir(&mut IrProgram::new(), &mut LoispContext::new());
+        assert!(matches!(
+            result,
+            Err(LoispError::ParserError(ParserError::InvalidSyntax(_)))
+        ));
+    }
+
+    #[test]
+    fn errors_convert_into_loisp_error_and_keep_their_source() {
+        let token = try_lex("oops", "test.loisp".to_string()).unwrap()[0].clone();
+        let errors: Vec<LoispError> = vec![
+            io::Error::other("disk on fire").into(),
+            ParserError::InvalidSyntax(token).into(),
+            EmulatorError::StringBufferExhausted(8).into(),
+        ];
+
+        for error in errors {
+            let message = error.to_string();
+            let boxed: Box<dyn error::Error> = Box::new(error);
+            let source = boxed.source().unwrap().to_string();
+            assert!(
+                message.contains(&source),
+                "{} doesn't show {}",
+                message,
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn standard_errors_show_the_message_of_the_io_error() {
+        let error: LoispError = io::Error::new(
+            io::ErrorKind::NotFound,
+            "Command `fasm` failed to execute: No such file or directory",
+        )
+        .into();
+        assert_eq!(
+            error.to_string(),
+            "ERROR: Command `fasm` failed to execute: No such file or directory"
+        );
+    }
+}
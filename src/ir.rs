@@ -1,3 +1,4 @@
+use super::bench::*;
 use super::common::*;
 use super::config::*;
 use super::instructions::*;
@@ -11,33 +12,41 @@ use std::io;
 use std::io::Write;
 use std::io::BufWriter;
 
-static IR_ASSERT_ENABLED: bool = false;
-static X86_64_RET_STACK_CAP: usize = 65536;
+pub static X86_64_RET_STACK_CAP: usize = 65536;
 
+// only checked with `--assert-ir`, and reported as an error
+// instead of a panic, since it means the IR is malformed
 macro_rules! assert_if_enabled {
-    ($($arg:tt)*) => {{
-        if IR_ASSERT_ENABLED {
-            assert!($($arg)*);
+    ($context:expr, $cond:expr, $($arg:tt)*) => {{
+        if $context.assert_ir && !$cond {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!($($arg)*),
+            ));
         }
     }};
 }
 
-pub fn syscall_number_as_register(n: i64) -> String {
+// the registers a syscall takes, in order: the syscall number and its arguments
+pub fn syscall_number_as_register(n: i64) -> Option<&'static str> {
     match n {
-        0 => "rax".to_string(),
-        1 => "rdi".to_string(),
-        2 => "rsi".to_string(),
-        3 => "rdx".to_string(),
-        4 => "r10".to_string(),
-        5 => "r8".to_string(),
-        6 => "r9".to_string(),
-        _ => "invalid".to_string(),
+        0 => Some("rax"),
+        1 => Some("rdi"),
+        2 => Some("rsi"),
+        3 => Some("rdx"),
+        4 => Some("r10"),
+        5 => Some("r8"),
+        6 => Some("r9"),
+        _ => None,
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum IrInstructionKind {
     Print,
+    EPrint,
+    PrintHex,
+    PrintBin,
     PushInteger,
     Plus,
     Minus,
@@ -54,8 +63,12 @@ pub enum IrInstructionKind {
     Store32,
     Load64,
     Store64,
+    Load8Signed,
+    Load16Signed,
+    Load32Signed,
     PushVariable,
     Jump,
+    JumpTable,
     Nop,
     If,
     Equal,
@@ -79,6 +92,8 @@ pub enum IrInstructionKind {
     Argc,
     Argv,
     Envp,
+    Drop,
+    LogicalNot,
 }
 
 #[derive(Debug, Clone)]
@@ -115,6 +130,8 @@ pub struct IrString {
 pub struct IrVariable {
     pub ident: usize,
     pub alloc: usize,
+    // what the memory is filled with when the program starts
+    pub data: Vec<u8>,
 }
 
 pub struct IrContext {
@@ -122,6 +139,10 @@ pub struct IrContext {
     pub memories: Vec<IrVariable>,
     pub strings: Vec<IrString>,
     pub label_count: i64,
+    // there is a label for each instruction, so the
+    // ones that jump can be checked against it
+    pub instruction_count: i64,
+    pub assert_ir: bool,
 }
 
 impl IrContext {
@@ -131,6 +152,8 @@ impl IrContext {
             memories: vec![],
             strings: vec![],
             label_count: 0,
+            instruction_count: 0,
+            assert_ir: false,
         }
     }
 }
@@ -156,8 +179,26 @@ impl IrInstruction {
             }
             Print => {
                 writeln!(f, "pop rdi")?;
+                writeln!(f, "mov r10, 1")?;
                 writeln!(f, "call print")?;
             }
+            EPrint => {
+                writeln!(f, "pop rdi")?;
+                writeln!(f, "mov r10, 2")?;
+                writeln!(f, "call print")?;
+            }
+            PrintHex => {
+                writeln!(f, "pop rdi")?;
+                writeln!(f, "mov rsi, 4")?;
+                writeln!(f, "mov r10, 1")?;
+                writeln!(f, "call print_bits")?;
+            }
+            PrintBin => {
+                writeln!(f, "pop rdi")?;
+                writeln!(f, "mov rsi, 1")?;
+                writeln!(f, "mov r10, 1")?;
+                writeln!(f, "call print_bits")?;
+            }
             Plus => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "pop rbx")?;
@@ -173,10 +214,11 @@ impl IrInstruction {
             Multiplication => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "pop rbx")?;
-                writeln!(f, "mul rbx")?;
+                writeln!(f, "imul rax, rbx")?;
                 writeln!(f, "push rax")?;
             }
             Division => {
+                writeln!(f, "xor rdx, rdx")?;
                 writeln!(f, "pop rax")?;
                 writeln!(f, "pop rbx")?;
                 writeln!(f, "div rbx")?;
@@ -191,7 +233,13 @@ impl IrInstruction {
             }
             Syscall => {
                 for i in 0..self.operand.integer {
-                    writeln!(f, "pop {}", syscall_number_as_register(i))?;
+                    let register = syscall_number_as_register(i).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("a syscall can't take {} registers", self.operand.integer),
+                        )
+                    })?;
+                    writeln!(f, "pop {}", register)?;
                 }
                 writeln!(f, "syscall")?;
                 writeln!(f, "push rax")?;
@@ -200,6 +248,7 @@ impl IrInstruction {
                 let variable = IrVariable {
                     ident: context.variables.len(),
                     alloc: self.operand.integer as usize,
+                    data: vec![],
                 };
                 context.variables.push(variable);
             }
@@ -207,6 +256,7 @@ impl IrInstruction {
                 let memory = IrVariable {
                     ident: context.memories.len(),
                     alloc: self.operand.integer as usize,
+                    data: escape_string(self.operand.string.clone()).into_bytes(),
                 };
                 context.memories.push(memory);
             }
@@ -222,46 +272,61 @@ impl IrInstruction {
             Load8 => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "xor rbx, rbx")?;
-                writeln!(f, "mov bl, [rax]")?;
+                writeln!(f, "mov bl, {}", self.address())?;
                 writeln!(f, "push rbx")?;
             }
             Store8 => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "pop rbx")?;
-                writeln!(f, "mov [rax], bl")?;
+                writeln!(f, "mov {}, bl", self.address())?;
             }
             Load16 => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "xor rbx, rbx")?;
-                writeln!(f, "mov bx, [rax]")?;
+                writeln!(f, "mov bx, {}", self.address())?;
                 writeln!(f, "push rbx")?;
             }
             Store16 => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "pop rbx")?;
-                writeln!(f, "mov [rax], bx")?;
+                writeln!(f, "mov {}, bx", self.address())?;
             }
             Load32 => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "xor rbx, rbx")?;
-                writeln!(f, "mov ebx, [rax]")?;
+                writeln!(f, "mov ebx, {}", self.address())?;
                 writeln!(f, "push rbx")?;
             }
             Store32 => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "pop rbx")?;
-                writeln!(f, "mov [rax], ebx")?;
+                writeln!(f, "mov {}, ebx", self.address())?;
             }
             Load64 => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "xor rbx, rbx")?;
-                writeln!(f, "mov rbx, [rax]")?;
+                writeln!(f, "mov rbx, {}", self.address())?;
+                writeln!(f, "push rbx")?;
+            }
+            Load8Signed => {
+                writeln!(f, "pop rax")?;
+                writeln!(f, "movsx rbx, byte {}", self.address())?;
+                writeln!(f, "push rbx")?;
+            }
+            Load16Signed => {
+                writeln!(f, "pop rax")?;
+                writeln!(f, "movsx rbx, word {}", self.address())?;
+                writeln!(f, "push rbx")?;
+            }
+            Load32Signed => {
+                writeln!(f, "pop rax")?;
+                writeln!(f, "movsxd rbx, dword {}", self.address())?;
                 writeln!(f, "push rbx")?;
             }
             Store64 => {
                 writeln!(f, "pop rax")?;
                 writeln!(f, "pop rbx")?;
-                writeln!(f, "mov [rax], rbx")?;
+                writeln!(f, "mov {}, rbx", self.address())?;
             }
             PushVariable => {
                 for v in &context.variables {
@@ -273,15 +338,38 @@ impl IrInstruction {
             }
             Jump => {
                 assert_if_enabled!(
-                    self.operand.integer <= context.label_count,
-                    "Label does not exist"
+                    context,
+                    (0..context.instruction_count).contains(&self.operand.integer),
+                    "jump to `addr_{}`, which does not exist",
+                    self.operand.integer
                 );
                 writeln!(f, "jmp addr_{}", self.operand.integer)?;
             }
+            JumpTable => {
+                // the table is followed by a jump for each of its entries,
+                // and then one for the values that are out of its range
+                assert_if_enabled!(
+                    context,
+                    context.label_count + self.operand.integer < context.instruction_count,
+                    "jump table at `addr_{}` goes past the end of the program",
+                    context.label_count - 1
+                );
+                writeln!(f, "pop rax")?;
+                writeln!(f, "cmp rax, {}", self.operand.integer)?;
+                writeln!(f, "jae addr_{}", context.label_count + self.operand.integer)?;
+                writeln!(f, "jmp qword [table_{}+rax*8]", context.label_count)?;
+                write!(f, "table_{}: dq ", context.label_count)?;
+                let entries: Vec<String> = (0..self.operand.integer)
+                    .map(|i| format!("addr_{}", context.label_count + i))
+                    .collect();
+                writeln!(f, "{}", entries.join(","))?;
+            }
             If => {
                 assert_if_enabled!(
-                    self.operand.integer <= context.label_count,
-                    "Label does not exist"
+                    context,
+                    (0..context.instruction_count).contains(&self.operand.integer),
+                    "jump to `addr_{}`, which does not exist",
+                    self.operand.integer
                 );
                 writeln!(f, "pop rax")?;
                 writeln!(f, "test rax, rax")?;
@@ -379,6 +467,12 @@ impl IrInstruction {
                 writeln!(f, "push str_{}", ident)?;
             }
             Call => {
+                assert_if_enabled!(
+                    context,
+                    (0..context.instruction_count).contains(&self.operand.integer),
+                    "call to `addr_{}`, which does not exist",
+                    self.operand.integer
+                );
                 writeln!(f, "mov rax, [ret_stack_rsp]")?;
                 writeln!(f, "sub rax, 8")?;
                 writeln!(f, "mov [ret_stack_rsp], rax")?;
@@ -414,6 +508,17 @@ impl IrInstruction {
                 writeln!(f, "add rbx, rax")?;
                 writeln!(f, "push rbx")?;
             }
+            Drop => {
+                writeln!(f, "pop rax")?;
+            }
+            LogicalNot => {
+                writeln!(f, "mov rcx, 0")?;
+                writeln!(f, "mov rdx, 1")?;
+                writeln!(f, "pop rax")?;
+                writeln!(f, "cmp rax, 0")?;
+                writeln!(f, "cmove rcx, rdx")?;
+                writeln!(f, "push rcx")?;
+            }
             Nop => {}
         }
 
@@ -425,7 +530,7 @@ impl IrInstruction {
         use IrInstructionKind::*;
 
         match self.kind {
-            Print => return Nothing,
+            Print | EPrint | PrintHex | PrintBin => return Nothing,
             PushInteger => return Integer,
             Plus => return Integer,
             Minus => return Integer,
@@ -442,8 +547,11 @@ impl IrInstruction {
             Store32 => return Nothing,
             Load64 => return Integer,
             Store64 => return Nothing,
+            Load8Signed => return Integer,
+            Load16Signed => return Integer,
+            Load32Signed => return Integer,
             PushVariable => return Pointer,
-            Jump => return Nothing,
+            Jump | JumpTable => return Nothing,
             Nop => return Nothing,
             If => return Nothing,
             Equal => return Integer,
@@ -467,6 +575,64 @@ impl IrInstruction {
             Argc => return Integer,
             Argv => return Pointer,
             Envp => return Pointer,
+            Drop => return Nothing,
+            LogicalNot => return Integer,
+        }
+    }
+
+    // how many values the instruction takes from the stack and how
+    // many it leaves on it, `None` when it can't be known statically
+    pub fn stack_arity(&self) -> Option<(i64, i64)> {
+        use IrInstructionKind::*;
+
+        match self.kind {
+            PushInteger | PushVariable | PushMemory | PushString | Argc | Argv | Envp => {
+                Some((0, 1))
+            }
+            AllocVariable | AllocMemory | Jump | Nop => Some((0, 0)),
+            Load8 | Load16 | Load32 | Load64 | Load8Signed | Load16Signed | Load32Signed | Not
+            | LogicalNot | CastPointer | CastInt => Some((1, 1)),
+            Print | EPrint | PrintHex | PrintBin | If | JumpTable | Drop => Some((1, 0)),
+            Plus | Minus | Multiplication | Division | Mod | Equal | NotEqual | Less | Greater
+            | LessEqual | GreaterEqual | ShiftLeft | ShiftRight | Or | And => Some((2, 1)),
+            Store8 | Store16 | Store32 | Store64 => Some((2, 0)),
+            Syscall => Some((self.operand.integer, 1)),
+            Call | Return => None,
+        }
+    }
+
+    // how many values the instruction leaves on the stack minus how
+    // many it takes from it, `None` when it can't be known statically
+    pub fn stack_effect(&self) -> Option<i64> {
+        self.stack_arity().map(|(pops, pushes)| pushes - pops)
+    }
+
+    pub fn operand_as_string(&self) -> String {
+        use IrInstructionKind::*;
+
+        match self.kind {
+            // the string still has the escapes of the source, like `\n`
+            PushString => format!("\"{}\"", self.operand.string),
+            PushInteger | PushVariable | PushMemory | AllocVariable | AllocMemory | Jump
+            | JumpTable | If | Call | Syscall => format!("{}", self.operand.integer),
+            // the offset of a load or a store, which is usually 0
+            Load8 | Load16 | Load32 | Load64 | Load8Signed | Load16Signed | Load32Signed
+            | Store8 | Store16 | Store32 | Store64
+                if self.operand.integer != 0 =>
+            {
+                format!("{:+}", self.operand.integer)
+            }
+            _ => String::new(),
+        }
+    }
+
+    // the memory that a load or a store accesses, at
+    // the address in `rax` plus the offset, if any
+    fn address(&self) -> String {
+        if self.operand.integer == 0 {
+            "[rax]".to_string()
+        } else {
+            format!("[rax{:+}]", self.operand.integer)
         }
     }
 }
@@ -487,6 +653,19 @@ impl IrProgram {
         self.instructions.push(i)
     }
 
+    // whether the program may call `exit`: the syscall number is pushed
+    // right before the syscall, and one that isn't a constant could be it
+    pub fn calls_exit(&self) -> bool {
+        use IrInstructionKind::*;
+
+        self.instructions.windows(2).any(|w| {
+            w[1].kind == Syscall
+                && (w[0].kind != PushInteger
+                    || w[0].operand.integer == 60
+                    || w[0].operand.integer == 231)
+        })
+    }
+
     pub fn to_fasm_linux_x86_64_assembly(
         &self,
         output: String,
@@ -507,6 +686,7 @@ impl IrProgram {
         let mut buffer = BufWriter::new(f);
 
         writeln!(buffer, "format ELF64 executable 3")?;
+        // prints rdi as an unsigned number to the fd in r10
         writeln!(buffer, "print:")?;
         writeln!(buffer, "mov r9, -3689348814741910323")?;
         writeln!(buffer, "sub rsp, 40")?;
@@ -531,7 +711,7 @@ impl IrProgram {
         writeln!(buffer, "cmp rax, 9")?;
         writeln!(buffer, "ja  .L2")?;
         writeln!(buffer, "lea rax, [rsp+32]")?;
-        writeln!(buffer, "mov edi, 1")?;
+        writeln!(buffer, "mov rdi, r10")?;
         writeln!(buffer, "sub rdx, rax")?;
         writeln!(buffer, "xor eax, eax")?;
         writeln!(buffer, "lea rsi, [rsp+32+rdx]")?;
@@ -540,24 +720,67 @@ impl IrProgram {
         writeln!(buffer, "syscall")?;
         writeln!(buffer, "add rsp, 40")?;
         writeln!(buffer, "ret")?;
+        // prints rdi as an unsigned number to the fd in r10, in base
+        // 2 to the power of rsi, so 1 is binary and 4 is hexadecimal
+        writeln!(buffer, "print_bits:")?;
+        writeln!(buffer, "mov rcx, rsi")?;
+        writeln!(buffer, "mov rdx, 1")?;
+        writeln!(buffer, "shl rdx, cl")?;
+        writeln!(buffer, "sub rdx, 1")?;
+        writeln!(buffer, "sub rsp, 72")?;
+        writeln!(buffer, "mov BYTE [rsp+71], 10")?;
+        writeln!(buffer, "lea r8, [rsp+70]")?;
+        writeln!(buffer, ".L1:")?;
+        writeln!(buffer, "mov rax, rdi")?;
+        writeln!(buffer, "and rax, rdx")?;
+        writeln!(buffer, "add rax, 48")?;
+        writeln!(buffer, "cmp rax, 57")?;
+        writeln!(buffer, "jbe .L2")?;
+        writeln!(buffer, "add rax, 39")?;
+        writeln!(buffer, ".L2:")?;
+        writeln!(buffer, "mov BYTE [r8], al")?;
+        writeln!(buffer, "sub r8, 1")?;
+        writeln!(buffer, "shr rdi, cl")?;
+        writeln!(buffer, "test rdi, rdi")?;
+        writeln!(buffer, "jnz .L1")?;
+        writeln!(buffer, "lea rsi, [r8+1]")?;
+        writeln!(buffer, "lea rdx, [rsp+72]")?;
+        writeln!(buffer, "sub rdx, rsi")?;
+        writeln!(buffer, "mov rdi, r10")?;
+        writeln!(buffer, "mov rax, 1")?;
+        writeln!(buffer, "syscall")?;
+        writeln!(buffer, "add rsp, 72")?;
+        writeln!(buffer, "ret")?;
         writeln!(buffer, "entry start")?;
         writeln!(buffer, "start:")?;
         writeln!(buffer, "mov [args_ptr], rsp")?;
         writeln!(buffer, "mov rax, ret_stack_end")?;
         writeln!(buffer, "mov [ret_stack_rsp], rax")?;
 
+        context.instruction_count = self.instructions.len() as i64;
+        context.assert_ir = config.assert_ir;
         for (k, i) in self.instructions.iter().enumerate() {
             writeln!(buffer, "addr_{}:", k)?;
             context.label_count += 1;
-            writeln!(buffer, ";; -- {:?} --", i.kind)?;
+            // the same text as in `disasm`, to find an instruction in both
+            let instruction = format!("{:?} {}", i.kind, i.operand_as_string());
+            writeln!(buffer, ";; -- {} --", instruction.trim_end())?;
             i.to_intel_linux_x86_64_assembly(&mut buffer, context)?;
         }
 
         writeln!(buffer, "mov rax, 60")?;
         writeln!(buffer, "mov rdi, 0")?;
         writeln!(buffer, "syscall")?;
+        // mark the stack as non-executable (PT_GNU_STACK)
+        writeln!(buffer, "segment gnustack")?;
         writeln!(buffer, "segment readable writable")?;
 
+        // the data is emitted in id order, so that building
+        // the same file twice gives the same assembly
+        context.strings.sort_by_key(|s| s.ident);
+        context.variables.sort_by_key(|v| v.ident);
+        context.memories.sort_by_key(|m| m.ident);
+
         // data
 
         for s in &context.strings {
@@ -575,7 +798,16 @@ impl IrProgram {
         }
 
         for m in &context.memories {
-            writeln!(buffer, "mem_{}: rb {}", m.ident, m.alloc)?;
+            if m.data.is_empty() {
+                writeln!(buffer, "mem_{}: rb {}", m.ident, m.alloc)?;
+            } else {
+                // the rest of the memory is the null terminator
+                write!(buffer, "mem_{}: db ", m.ident)?;
+                for c in &m.data {
+                    write!(buffer, "0x{:02x},", c)?;
+                }
+                writeln!(buffer, "0x00")?;
+            }
         }
 
         writeln!(buffer, "args_ptr: rb 8")?;
@@ -589,76 +821,234 @@ impl IrProgram {
     }
 }
 
-pub fn compile_file_into_existing_ir(
-    f: String,
-    ir: &mut IrProgram,
-    context: &mut LoispContext,
-) -> io::Result<()> {
-    let source = fs::read_to_string(f.as_str())?;
-    let lexer = Lexer::from_chars(source.chars(), f);
+pub fn read_source_file(f: &str) -> Result<String, LoispError> {
+    match fs::read_to_string(f) {
+        Ok(source) => Ok(source),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            Err(LoispError::FileNotFound(f.to_string()))
+        }
+        Err(error) => Err(LoispError::CouldNotReadFile(f.to_string(), error)),
+    }
+}
+
+pub fn lex_file(config: Config) -> Result<(), LoispError> {
+    let source = read_source_file(config.input.as_str())?;
+    let mut lexer = Lexer::from_chars(source.chars(), config.input.clone());
+
+    if config.emit_tokens_json {
+        let tokens: Vec<String> = lexer.by_ref().map(|t| t.to_json()).collect();
+        println!("[{}]", tokens.join(",\n "));
+    } else {
+        for t in lexer.by_ref() {
+            println!("{}", t);
+        }
+    }
 
-    let result = construct_instructions_from_tokens(&mut lexer.peekable());
-    if let Err(error) = result {
-        eprintln!("{}", error);
-        std::process::exit(1);
+    if let Some(error) = lexer.error {
+        return Err(ParserError::LexerError(error).into());
     }
-    let instructions = result.unwrap();
 
+    Ok(())
+}
+
+pub fn type_at(source: String, r: i64, c: i64) -> Option<LoispDatatype> {
+    let instructions = try_parse(source.as_str(), "<hover>".to_string()).ok()?;
+
+    // lower everything that comes before the queried instruction,
+    // so the definitions it may refer to are known
+    let mut context = LoispContext::new();
+    let mut ir = IrProgram::new();
     for i in instructions {
-        let result = i.to_ir(ir, context);
-        if let Err(error) = result {
-            eprintln!("{}", error);
-            std::process::exit(1);
+        if let Some(found) = i.instruction_at(r, c) {
+            return Some(found.return_type(&mut context));
         }
+        i.to_ir(&mut ir, &mut context).ok()?;
     }
 
+    None
+}
+
+pub fn print_type_at(config: Config, r: i64, c: i64) -> Result<(), LoispError> {
+    let source = read_source_file(config.input.as_str())?;
+    if let Some(typ) = type_at(source, r, c) {
+        println!("{:?}", typ);
+    } else {
+        println!("<none>");
+    }
     Ok(())
 }
 
-pub fn compile_file_into_ir(f: String) -> io::Result<IrProgram> {
-    let source = fs::read_to_string(f.as_str())?;
-    let lexer = Lexer::from_chars(source.chars(), f);
+pub fn disassemble_file(config: Config) -> Result<(), LoispError> {
     let mut context = LoispContext::new();
+    let ir = compile_file_into_ir(config.input, &mut context, &mut Timings::new())?;
 
-    let result = construct_instructions_from_tokens(&mut lexer.peekable());
-    if let Err(error) = result {
-        eprintln!("{}", error);
-        std::process::exit(1);
+    for (addr, i) in ir.instructions.iter().enumerate() {
+        let effect = match i.stack_arity() {
+            Some((pops, 0)) => format!("pops {}, pushes 0, net {:+}", pops, -pops),
+            Some((pops, pushes)) => format!(
+                "pops {}, pushes {} ({:?}), net {:+}",
+                pops,
+                pushes,
+                i.get_loisp_datatype(),
+                pushes - pops
+            ),
+            None => "unknown stack effect".to_string(),
+        };
+
+        let instruction = format!("{:?} {}", i.kind, i.operand_as_string());
+        println!("{:>5}: {:<24} ; {}", addr, instruction.trim_end(), effect);
     }
-    let instructions = result.unwrap();
 
-    let mut ir = IrProgram::new();
+    Ok(())
+}
+
+pub fn compile_file_into_existing_ir(
+    f: String,
+    ir: &mut IrProgram,
+    context: &mut LoispContext,
+) -> Result<(), LoispError> {
+    let source = read_source_file(f.as_str())?;
+    let instructions = try_parse(source.as_str(), f)?;
+
     for i in instructions {
-        let result = i.to_ir(&mut ir, &mut context);
-        if let Err(error) = result {
-            eprintln!("{}", error);
-            std::process::exit(1);
-        }
+        i.to_ir(ir, context)?;
+        drop_unused_value(i.return_type(context), ir);
     }
 
-    Ok(ir)
+    Ok(())
 }
 
-pub fn compile_string_into_ir(string: String, context: &mut LoispContext, f: String) -> Result<IrProgram, LoispError> {
-    let lexer = Lexer::from_chars(string.chars(), f);
-    let instructions = construct_instructions_from_tokens(&mut lexer.peekable())?;
+pub fn compile_file_into_ir(
+    f: String,
+    context: &mut LoispContext,
+    timings: &mut Timings,
+) -> Result<IrProgram, LoispError> {
+    let source = read_source_file(f.as_str())?;
+    let tokens = timings.time("lexing", || try_lex(source.as_str(), f))?;
+    let instructions = timings.time("parsing", || {
+        construct_instructions_from_tokens(&mut tokens.into_iter().peekable())
+    })?;
+
+    timings.time("lowering", || lower_instructions(instructions, context))
+}
 
+pub fn lower_instructions(
+    instructions: Vec<LoispInstruction>,
+    context: &mut LoispContext,
+) -> Result<IrProgram, LoispError> {
     let mut ir = IrProgram::new();
     for i in instructions {
         i.to_ir(&mut ir, context)?;
+        drop_unused_value(i.return_type(context), &mut ir);
     }
 
     Ok(ir)
 }
 
-pub fn compile_file_into_assembly(i: &str, o: &str, config: Config) -> io::Result<()> {
+pub fn print_stats(ir: &IrProgram, context: &LoispContext, assembly_lines: Option<usize>) {
+    let count = |kind: IrInstructionKind| ir.instructions.iter().filter(|i| i.kind == kind).count();
+
+    print_info!("STATS", "IR instructions: {}", ir.instructions.len());
+    print_info!("STATS", "Strings: {}", count(IrInstructionKind::PushString));
+    print_info!(
+        "STATS",
+        "Variables: {}",
+        count(IrInstructionKind::AllocVariable)
+    );
+    print_info!(
+        "STATS",
+        "Memories: {}",
+        count(IrInstructionKind::AllocMemory)
+    );
+    print_info!("STATS", "Functions: {}", context.functions.len());
+    print_info!("STATS", "Macros: {}", context.macros.len());
+    if let Some(lines) = assembly_lines {
+        print_info!("STATS", "Assembly lines: {}", lines);
+    }
+}
+
+pub fn dump_context(context: &LoispContext) {
+    // the maps are sorted by name so the dump is stable between runs
+    let sorted = |names: Vec<&String>| {
+        let mut names: Vec<String> = names.into_iter().cloned().collect();
+        names.sort();
+        names
+    };
+
+    let mut types = context.clone();
+    for name in sorted(context.variables.keys().collect()) {
+        let variable = &context.variables[&name];
+        print_info!(
+            "CONTEXT",
+            "Variable `{}`: id {}, {:?}",
+            name,
+            variable.id,
+            variable.value.datatype(&mut types).unwrap()
+        );
+    }
+    for name in sorted(context.memories.keys().collect()) {
+        print_info!(
+            "CONTEXT",
+            "Memory `{}`: {} bytes",
+            name,
+            context.memories[&name].alloc
+        );
+    }
+    for name in sorted(context.macros.keys().collect()) {
+        print_info!(
+            "CONTEXT",
+            "Macro `{}`: {} instructions",
+            name,
+            context.macros[&name].program.instructions.len()
+        );
+    }
+    for name in sorted(context.functions.keys().collect()) {
+        let function = &context.functions[&name];
+        print_info!(
+            "CONTEXT",
+            "Function `{}`: address {}, returns {:?}",
+            name,
+            function.addr,
+            function.typ
+        );
+    }
+}
+
+pub fn compile_file_into_assembly(
+    i: &str,
+    o: &str,
+    config: Config,
+    timings: &mut Timings,
+) -> Result<(), LoispError> {
     let mut context = IrContext::new();
-    let ir = compile_file_into_ir(i.to_string())?;
-    ir.to_fasm_linux_x86_64_assembly(o.to_string(), config.clone(), &mut context)?;
+    let mut loisp_context = LoispContext::new();
+    if config.no_std_search {
+        loisp_context.search_paths.clear();
+    }
+    loisp_context.allow_redefine = config.allow_redefine;
+    let ir = compile_file_into_ir(i.to_string(), &mut loisp_context, timings)?;
+    timings.time("codegen", || {
+        ir.to_fasm_linux_x86_64_assembly(o.to_string(), config.clone(), &mut context)
+    })?;
+
+    if config.stats {
+        let assembly_lines = fs::read_to_string(o)?.lines().count();
+        print_stats(&ir, &loisp_context, Some(assembly_lines));
+    }
+
+    if config.dump_context {
+        dump_context(&loisp_context);
+    }
+
     Ok(())
 }
 
-pub fn compile_file_into_executable(config: Config) -> io::Result<()> {
+pub fn compile_file_into_executable(config: Config) -> Result<i32, LoispError> {
+    // the generated executable relies on Unix permissions
+    if !cfg!(unix) {
+        return Err(LoispError::UnsupportedHost);
+    }
+
     let config_output: String;
     {
         let c = config.clone();
@@ -672,31 +1062,97 @@ pub fn compile_file_into_executable(config: Config) -> io::Result<()> {
     let output_executable = format!("{}.tmp", config_output);
     let final_output_executable = format!("{}.out", config_output);
 
+    let mut timings = Timings::new();
     compile_file_into_assembly(
         config.input.as_str(),
         output_assembly.as_str(),
         config.clone(),
+        &mut timings,
     )?;
 
-    let assembler_command = format!("fasm -m 524288 {} {}", output_assembly, output_executable);
-    let chmod_command = format!("chmod +x {}", output_executable);
-    let rename_command = format!("mv {} {}", output_executable, final_output_executable);
+    let assembler_command = [
+        "fasm".to_string(),
+        "-m".to_string(),
+        "524288".to_string(),
+        output_assembly.clone(),
+        output_executable.clone(),
+    ];
+
+    timings.time("assembling", || {
+        run_program_with_info(&assembler_command, config.clone())
+    })?;
 
-    run_command_with_info(assembler_command, config.clone())?;
-    run_command_with_info(chmod_command, config.clone())?;
-    run_command_with_info(rename_command, config.clone())?;
+    if config.timings {
+        timings.print();
+    }
+
+    finish_executable(output_executable.as_str(), final_output_executable.as_str())?;
 
+    let mut code = 0;
     {
         let mut c = config.clone();
         c.piped = false;
         if config.run.run {
-            let mut command = format!("./{}", final_output_executable);
-            for a in config.run.args {
-                command = format!("{} {}", command, a);
+            let mut command = vec![executable_path(final_output_executable.as_str())];
+            command.extend(config.run.args);
+            code = exit_status_as_code(run_program_with_status(&command, c)?);
+        }
+    }
+
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assembly_of(input: &str, output: &str) -> Vec<u8> {
+        let mut config = Config::new();
+        config.silent = true;
+        compile_file_into_assembly(input, output, config, &mut Timings::new()).unwrap();
+        let assembly = fs::read(output).unwrap();
+        fs::remove_file(output).unwrap();
+        assembly
+    }
+
+    #[test]
+    fn assembly_is_the_same_every_time() {
+        // every compilation makes new `HashMap`s, which are
+        // iterated in a different order each time
+        let output = std::env::temp_dir()
+            .join(format!("loisp-test-{}.asm", std::process::id()))
+            .display()
+            .to_string();
+
+        for input in ["tests/allocation-order.loisp", "tests/strings.loisp"] {
+            let first = assembly_of(input, &output);
+            for _ in 0..4 {
+                assert!(first == assembly_of(input, &output), "`{}` changed", input);
             }
-            run_command_with_info(command, c)?;
         }
     }
 
-    Ok(())
+    #[test]
+    fn assembly_marks_the_stack_as_not_executable() {
+        let output = std::env::temp_dir()
+            .join(format!("loisp-test-gnustack-{}.asm", std::process::id()))
+            .display()
+            .to_string();
+
+        let assembly = String::from_utf8(assembly_of("tests/empty.loisp", &output)).unwrap();
+        assert!(assembly.lines().any(|l| l == "segment gnustack"));
+    }
+
+    #[test]
+    fn assembly_names_each_instruction_like_disasm() {
+        let output = std::env::temp_dir()
+            .join(format!("loisp-test-comments-{}.asm", std::process::id()))
+            .display()
+            .to_string();
+
+        let assembly = String::from_utf8(assembly_of("tests/arithmetics.loisp", &output)).unwrap();
+        assert!(assembly.lines().any(|l| l == ";; -- PushInteger 34 --"));
+        // an instruction without an operand has no trailing space
+        assert!(assembly.lines().any(|l| l == ";; -- Plus --"));
+    }
 }
@@ -1,5 +1,6 @@
 use std::fmt;
 use std::iter::Peekable;
+use std::num::IntErrorKind;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum LexerTokenKind {
@@ -14,6 +15,18 @@ pub fn is_special_token(c: char) -> bool {
     c == '(' || c == ')' || c == ' ' || c == '\n' || c == '\r' || c == '#' || c == '"'
 }
 
+// `1_000_000`, but not `_1000`, which is a valid name
+pub fn is_integer_with_underscores(text: &str) -> bool {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let starts_like_integer =
+        text.starts_with('-') || digits.starts_with(|c: char| c.is_ascii_digit());
+
+    starts_like_integer
+        && digits.contains('_')
+        && digits.contains(|c: char| c.is_ascii_digit())
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '_')
+}
+
 #[derive(Debug, Clone)]
 pub struct LexerTokenValue {
     pub integer: i64,
@@ -63,9 +76,98 @@ pub struct LexerToken {
     pub location: LexerLocation,
 }
 
+impl fmt::Display for LexerToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.kind {
+            LexerTokenKind::Integer => write!(
+                f,
+                "{}: {:?}: {}",
+                self.location, self.kind, self.value.integer
+            )?,
+            LexerTokenKind::String => write!(
+                f,
+                "{}: {:?}: \"{}\"",
+                self.location, self.kind, self.value.string
+            )?,
+            _ => write!(
+                f,
+                "{}: {:?}: {}",
+                self.location, self.kind, self.value.string
+            )?,
+        }
+        Ok(())
+    }
+}
+
+pub fn json_escape_string(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl LexerToken {
+    pub fn to_json(&self) -> String {
+        let value = if self.kind == LexerTokenKind::Integer {
+            format!("{}", self.value.integer)
+        } else {
+            format!("\"{}\"", json_escape_string(self.value.string.as_str()))
+        };
+
+        format!(
+            "{{\"kind\":\"{:?}\",\"value\":{},\"file\":\"{}\",\"row\":{},\"col\":{}}}",
+            self.kind,
+            value,
+            json_escape_string(self.location.f.as_str()),
+            self.location.r,
+            self.location.c
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LexerError {
+    UnterminatedString(LexerLocation),
+    IntegerTooLarge(LexerLocation, String),
+    MisplacedUnderscore(LexerLocation, String),
+}
+
+impl fmt::Display for LexerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Self::UnterminatedString(location) => {
+                write!(f, "{}: ERROR: Reached EOF while parsing string", location)?
+            }
+            Self::IntegerTooLarge(location, text) => write!(
+                f,
+                "{}: ERROR: Integer literal `{}` is too large to fit in 64 bits",
+                location, text
+            )?,
+            Self::MisplacedUnderscore(location, text) => write!(
+                f,
+                "{}: ERROR: Integer literal `{}` has a misplaced `_`, underscores can only separate digits",
+                location, text
+            )?,
+        }
+        Ok(())
+    }
+}
+
 pub struct Lexer<Chars: Iterator<Item = char>> {
     pub chars: Peekable<Chars>,
     pub location: LexerLocation,
+    // the lexer stops producing tokens at the first error,
+    // which is kept here for the caller to report
+    pub error: Option<LexerError>,
 }
 
 impl<Chars: Iterator<Item = char>> Lexer<Chars> {
@@ -73,6 +175,7 @@ impl<Chars: Iterator<Item = char>> Lexer<Chars> {
         Self {
             chars: chars.peekable(),
             location: LexerLocation::new(f),
+            error: None,
         }
     }
 
@@ -120,6 +223,10 @@ impl<Chars: Iterator<Item = char>> Iterator for Lexer<Chars> {
 
     fn next(&mut self) -> Option<LexerToken> {
         use LexerTokenKind::*;
+        if self.error.is_some() {
+            return None;
+        }
+
         self.ignore_whitespaces_and_comments();
 
         if let Some(x) = self.chars.next() {
@@ -150,8 +257,8 @@ impl<Chars: Iterator<Item = char>> Iterator for Lexer<Chars> {
                     if let Some(_) = self.chars.peek() {
                         self.chars.next();
                     } else {
-                        eprintln!("ERROR: Reached EOF while parsing string");
-                        std::process::exit(1);
+                        self.error = Some(LexerError::UnterminatedString(self.location.clone()));
+                        return None;
                     }
                     Some(LexerToken {
                         kind: LexerTokenKind::String,
@@ -165,10 +272,32 @@ impl<Chars: Iterator<Item = char>> Iterator for Lexer<Chars> {
                         self.advance_location(x);
                     }
 
-                    let parsed = text.parse::<i64>();
                     let mut location = self.location.clone();
                     location.c -= (text.len() as i64) - 1;
-                    if let Err(_) = parsed {
+
+                    let mut digits = text.clone();
+                    if is_integer_with_underscores(&text) {
+                        let unsigned = text.strip_prefix('-').unwrap_or(&text);
+                        if unsigned.starts_with('_')
+                            || unsigned.ends_with('_')
+                            || unsigned.contains("__")
+                        {
+                            self.error = Some(LexerError::MisplacedUnderscore(location, text));
+                            return None;
+                        }
+                        digits = text.replace('_', "");
+                    }
+
+                    let parsed = digits.parse::<i64>();
+                    if let Err(error) = &parsed {
+                        if matches!(
+                            error.kind(),
+                            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow
+                        ) {
+                            self.error = Some(LexerError::IntegerTooLarge(location, text));
+                            return None;
+                        }
+
                         Some(LexerToken {
                             kind: Word,
                             value: LexerTokenValue::from_string(text),
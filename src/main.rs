@@ -1,3 +1,4 @@
+mod bench;
 mod common;
 mod config;
 mod instructions;
@@ -9,6 +10,7 @@ mod types;
 mod emulator;
 mod repl;
 
+use bench::*;
 use config::*;
 use instructions::*;
 use ir::*;
@@ -21,16 +23,35 @@ use std::ffi::OsString;
 
 fn usage(stderr: bool) {
     let help = "Usage: loisp [FLAGS] <SUBCOMMAND>
+    Any argument `@<file>` is replaced with the whitespace separated arguments in <file>,
+    and `@@<arg>` is passed as `@<arg>`
     Subcommands:
         build   <file>     Compile <file> into an executable
         run     <file>     Compile <file> into an executable and run the generated executable
         emulate <file>     Emulate <file>
+        lex     <file>     Print the tokens of <file>
+        type-at <file> <row> <col> Print the type of the expression at <row>:<col> in <file>
+        disasm  <file>     Print the IR of <file> with the stack effect of each instruction
+        bench   <file> [count] [--emulate] Compile <file> [count] times (10 by default) and print how long each phase took
         save-test <folder> Save test cases for each file in <folder>
         run-test  <folder> Run tests for each file in <folder>
         help               Prints this help to stdout and exits with 0 exit code
     Flags:
-        -s             Do not show any output (except errors)
-        -o <file>      Change the name of the executable that gets generated\n";
+        -s                 Do not show any output (except errors)
+        -o <file>          Change the name of the executable that gets generated
+        --emit-tokens-json Make `lex` print the tokens as JSON
+        --stats            Print statistics about the compiled program
+        --count            Print how many IR instructions `emulate` executed
+        --profile          Print the IR instructions that `emulate` executed the most times
+        --dump-context     Print the variables, memories, macros and functions of the compiled program
+        --timings          Print how long each phase of `build`, `run` and `emulate` took
+        --assert-ir        Check that the IR only jumps to instructions that exist while generating assembly
+        --allow-redefine   Warn when a variable, memory, macro or function is defined again and replace it, instead of failing
+        --no-std-search    Only look for included files at the given path, not in `.`, `..`, `./std` or `../std`
+        --strict-stores    Make `emulate` fail when a value doesn't fit in the width it is stored with
+        --mem-size <n>     Set the size in bytes of the emulator's buffer for `alloc`
+        --string-size <n>  Set the size in bytes of the emulator's buffer for strings
+        --var-size <n>     Set the size in bytes of the emulator's buffer for variables\n";
     if stderr {
         eprint!("{}", help)
     } else {
@@ -47,9 +68,49 @@ fn shift(args: &mut Vec<OsString>) -> Option<String> {
     Some(r.to_str().unwrap().to_string())
 }
 
-fn main() -> Result<(), LoispError> {
+fn shift_size(args: &mut Vec<OsString>, flag: &str) -> usize {
+    if let Some(size) = shift(args).and_then(|s| s.parse::<usize>().ok()) {
+        size
+    } else {
+        usage(true);
+        eprintln!("ERROR: Expected a size in bytes after `{}`", flag);
+        std::process::exit(1)
+    }
+}
+
+// `@path` is replaced with the whitespace separated arguments in the
+// file at `path`, which are used as they are, even if they start with `@`
+fn expand_response_files(args: Vec<OsString>) -> Result<Vec<OsString>, LoispError> {
+    let mut expanded = vec![];
+    for arg in args {
+        if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix('@')) {
+            if path.starts_with('@') {
+                expanded.push(OsString::from(path));
+                continue;
+            }
+            let contents = read_source_file(path)?;
+            expanded.extend(contents.split_whitespace().map(OsString::from));
+        } else {
+            expanded.push(arg);
+        }
+    }
+    Ok(expanded)
+}
+
+fn main() {
+    match run_loisp() {
+        Ok(code) => std::process::exit(code),
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_loisp() -> Result<i32, LoispError> {
     let mut args: Vec<OsString> = env::args_os().collect();
     shift(&mut args);
+    let mut args = expand_response_files(args)?;
 
     if args.len() < 1 {
         start_repl();
@@ -59,8 +120,19 @@ fn main() -> Result<(), LoispError> {
         let mut silent = false;
         let mut piped = false;
         let mut emulate = false;
+        let mut lex = false;
+        let mut emit_tokens_json = false;
+        let mut stats = false;
+        let mut count = false;
+        let mut profile = false;
+        let mut dump_context = false;
+        let mut no_std_search = false;
+        let mut timings = false;
+        let mut assert_ir = false;
+        let mut allow_redefine = false;
         let mut input = String::new();
         let mut output = None;
+        let mut buffers = ConfigEmulator::new();
         while args.len() > 0 {
             if let Some(arg) = shift(&mut args) {
                 match arg.as_str() {
@@ -102,6 +174,76 @@ fn main() -> Result<(), LoispError> {
                         }
                         break;
                     }
+                    "lex" => {
+                        if let Some(i) = shift(&mut args) {
+                            input = i;
+                            lex = true;
+                        } else {
+                            usage(true);
+                            eprintln!("ERROR: No input file was provided");
+                            std::process::exit(1);
+                        }
+                        break;
+                    }
+                    "type-at" => {
+                        let i = shift(&mut args);
+                        let r = shift(&mut args).and_then(|r| r.parse::<i64>().ok());
+                        let c = shift(&mut args).and_then(|c| c.parse::<i64>().ok());
+                        if let (Some(i), Some(r), Some(c)) = (i, r, c) {
+                            let mut config = Config::new();
+                            config.input = i;
+                            print_type_at(config, r, c)?;
+                            std::process::exit(0);
+                        } else {
+                            usage(true);
+                            eprintln!("ERROR: Expected an input file, a row and a column");
+                            std::process::exit(1);
+                        }
+                    }
+                    "disasm" => {
+                        if let Some(i) = shift(&mut args) {
+                            let mut config = Config::new();
+                            config.input = i;
+                            disassemble_file(config)?;
+                            std::process::exit(0);
+                        } else {
+                            usage(true);
+                            eprintln!("ERROR: No input file was provided");
+                            std::process::exit(1);
+                        }
+                    }
+                    "bench" => {
+                        let mut config = Config::new();
+                        let mut count = DEFAULT_BENCH_COUNT;
+                        if let Some(i) = shift(&mut args) {
+                            config.input = i;
+                        } else {
+                            usage(true);
+                            eprintln!("ERROR: No input file was provided");
+                            std::process::exit(1);
+                        }
+                        while let Some(arg) = shift(&mut args) {
+                            if arg == "--emulate" {
+                                config.emulate = true;
+                            } else if let Some(c) = arg.parse::<usize>().ok().filter(|c| *c > 0) {
+                                count = c;
+                            } else {
+                                usage(true);
+                                eprintln!(
+                                    "ERROR: Expected a positive count or `--emulate` but got `{}`",
+                                    arg
+                                );
+                                std::process::exit(1);
+                            }
+                        }
+                        config.emulator = buffers;
+                        config.no_std_search = no_std_search;
+                        config.timings = timings;
+                        config.assert_ir = assert_ir;
+                        config.allow_redefine = allow_redefine;
+                        bench_file(config, count)?;
+                        std::process::exit(0);
+                    }
                     "save-test" => {
                         if let Some(i) = shift(&mut args) {
                             save_tests_for_folder(i)?;
@@ -130,6 +272,19 @@ fn main() -> Result<(), LoispError> {
                         silent = true;
                         piped = true
                     }
+                    "--emit-tokens-json" => emit_tokens_json = true,
+                    "--stats" => stats = true,
+                    "--count" => count = true,
+                    "--profile" => profile = true,
+                    "--dump-context" => dump_context = true,
+                    "--no-std-search" => no_std_search = true,
+                    "--timings" => timings = true,
+                    "--assert-ir" => assert_ir = true,
+                    "--allow-redefine" => allow_redefine = true,
+                    "--strict-stores" => buffers.strict_stores = true,
+                    "--mem-size" => buffers.memory_capacity = shift_size(&mut args, &arg),
+                    "--string-size" => buffers.string_capacity = shift_size(&mut args, &arg),
+                    "--var-size" => buffers.variable_capacity = shift_size(&mut args, &arg),
                     "-o" => {
                         if let Some(o) = shift(&mut args) {
                             output = Some(o)
@@ -154,19 +309,35 @@ fn main() -> Result<(), LoispError> {
         config.silent = silent;
         config.piped = piped;
         config.output = output;
+        config.emulator = buffers;
         config.input = input;
         config.emulate = emulate;
+        config.lex = lex;
+        config.emit_tokens_json = emit_tokens_json;
+        config.stats = stats;
+        config.count = count;
+        config.profile = profile;
+        config.dump_context = dump_context;
+        config.no_std_search = no_std_search;
+        config.timings = timings;
+        config.assert_ir = assert_ir;
+        config.allow_redefine = allow_redefine;
+
+        if config.lex {
+            lex_file(config)?;
+            return Ok(0);
+        }
 
         if !config.silent && !config.emulate {
             print_info!("INFO", "Compiling `{}`", config.input);
         }
 
         if !config.emulate {
-            compile_file_into_executable(config)?;
+            return compile_file_into_executable(config);
         } else {
             emulate_file(config)?;
         }
     }
 
-    Ok(())
+    Ok(0)
 }
@@ -2,24 +2,32 @@ use super::instructions::*;
 use super::lexer::*;
 use super::lexer_type;
 
+use std::error;
 use std::fmt;
 use std::iter::Peekable;
 
 #[derive(Debug)]
 pub enum ParserError {
     InvalidSyntax(LexerToken),
-    UnmatchedParenthesis(LexerToken),
+    UnexpectedCloseParen(LexerToken),
+    UnclosedOpenParen(LexerToken),
     ReachedEOF(LexerToken),
     ExpectedNameToBeWord(LexerToken),
     UnknownInstruction(LexerToken),
+    LexerError(LexerError),
 }
 
 impl fmt::Display for ParserError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
             Self::InvalidSyntax(token) => write!(f, "{}: ERROR: Invalid syntax", token.location)?,
-            Self::UnmatchedParenthesis(token) => {
-                write!(f, "{}: ERROR: Unmatched parenthesis", token.location)?
+            Self::UnexpectedCloseParen(token) => write!(
+                f,
+                "{}: ERROR: Unexpected `)` that doesn't close any parenthesis",
+                token.location
+            )?,
+            Self::UnclosedOpenParen(token) => {
+                write!(f, "{}: ERROR: This `(` is never closed", token.location)?
             }
             Self::ExpectedNameToBeWord(token) => write!(
                 f,
@@ -34,14 +42,20 @@ impl fmt::Display for ParserError {
                 "{}: ERROR: Unknown instruction: {}",
                 token.location, token.value.string
             )?,
+            Self::LexerError(error) => write!(f, "{}", error)?,
         }
         Ok(())
     }
 }
 
+impl error::Error for ParserError {}
+
 pub fn token_to_instruction_kind(token: LexerToken) -> Result<LoispInstructionType, ParserError> {
     match token.value.string.as_str() {
         "print" => Ok(LoispInstructionType::Print),
+        "eprint" => Ok(LoispInstructionType::EPrint),
+        "printhex" => Ok(LoispInstructionType::PrintHex),
+        "printbin" => Ok(LoispInstructionType::PrintBin),
         "+" => Ok(LoispInstructionType::Plus),
         "-" => Ok(LoispInstructionType::Minus),
         "*" => Ok(LoispInstructionType::Multiplication),
@@ -68,8 +82,20 @@ pub fn token_to_instruction_kind(token: LexerToken) -> Result<LoispInstructionTy
         "load16" => Ok(LoispInstructionType::Load16),
         "store16" => Ok(LoispInstructionType::Store16),
         "load8" => Ok(LoispInstructionType::Load8),
+        "load32s" => Ok(LoispInstructionType::Load32Signed),
+        "load64off" => Ok(LoispInstructionType::Load64Offset),
+        "store64off" => Ok(LoispInstructionType::Store64Offset),
+        "load32off" => Ok(LoispInstructionType::Load32Offset),
+        "store32off" => Ok(LoispInstructionType::Store32Offset),
+        "load16off" => Ok(LoispInstructionType::Load16Offset),
+        "store16off" => Ok(LoispInstructionType::Store16Offset),
+        "load8off" => Ok(LoispInstructionType::Load8Offset),
+        "store8off" => Ok(LoispInstructionType::Store8Offset),
+        "load16s" => Ok(LoispInstructionType::Load16Signed),
+        "load8s" => Ok(LoispInstructionType::Load8Signed),
         "store8" => Ok(LoispInstructionType::Store8),
         "alloc" => Ok(LoispInstructionType::Alloc),
+        "allocstr" => Ok(LoispInstructionType::AllocStr),
         "getmem" => Ok(LoispInstructionType::GetMem),
         "castint" => Ok(LoispInstructionType::CastInt),
         "castptr" => Ok(LoispInstructionType::CastPointer),
@@ -85,11 +111,38 @@ pub fn token_to_instruction_kind(token: LexerToken) -> Result<LoispInstructionTy
         "defun" => Ok(LoispInstructionType::DefFun),
         "call" => Ok(LoispInstructionType::Call),
         "increment" => Ok(LoispInstructionType::Increment),
+        "decrement" => Ok(LoispInstructionType::Decrement),
+        "+=" => Ok(LoispInstructionType::PlusAssign),
+        "-=" => Ok(LoispInstructionType::MinusAssign),
+        "*=" => Ok(LoispInstructionType::MultiplicationAssign),
+        "/=" => Ok(LoispInstructionType::DivisionAssign),
         "reset" => Ok(LoispInstructionType::Reset),
         "typeof" => Ok(LoispInstructionType::TypeOf),
         "argc" => Ok(LoispInstructionType::Argc),
         "argv" => Ok(LoispInstructionType::Argv),
         "envp" => Ok(LoispInstructionType::Envp),
+        "pragma" => Ok(LoispInstructionType::Pragma),
+        "load" => Ok(LoispInstructionType::Load),
+        "store" => Ok(LoispInstructionType::Store),
+        "fopen" => Ok(LoispInstructionType::FOpen),
+        "fread" => Ok(LoispInstructionType::FRead),
+        "fwrite" => Ok(LoispInstructionType::FWrite),
+        "fclose" => Ok(LoispInstructionType::FClose),
+        "strcat" => Ok(LoispInstructionType::StrCat),
+        "lnot" => Ok(LoispInstructionType::LogicalNot),
+        "strbyte" => Ok(LoispInstructionType::StrByte),
+        "argat" => Ok(LoispInstructionType::ArgAt),
+        "assert" => Ok(LoispInstructionType::Assert),
+        "return" => Ok(LoispInstructionType::Return),
+        "returns" => Ok(LoispInstructionType::Returns),
+        "switch" => Ok(LoispInstructionType::Switch),
+        "case" => Ok(LoispInstructionType::Case),
+        "default" => Ok(LoispInstructionType::Default),
+        "array" => Ok(LoispInstructionType::Array),
+        "aget" => Ok(LoispInstructionType::ArrayGet),
+        "aset" => Ok(LoispInstructionType::ArraySet),
+        "struct" => Ok(LoispInstructionType::Struct),
+        "field" => Ok(LoispInstructionType::Field),
         _ => Err(ParserError::UnknownInstruction(token.clone())),
     }
 }
@@ -120,19 +173,23 @@ pub fn parse_instruction(
         instruction.kind = token_to_instruction_kind(name.clone())?;
 
         let mut closed = false;
-        let location = name.clone();
 
         while let Some(next) = lexer.next() {
             use LexerTokenKind::*;
 
             match next.kind {
                 CloseParen => {
+                    instruction.end = next.location.clone();
                     closed = true;
                     break;
                 }
                 OpenParen => {
                     let mut value = LoispValue::new(next.clone());
-                    value.instruction_return = Some(parse_instruction(lexer, next.clone())?);
+                    if instruction.kind == LoispInstructionType::Struct {
+                        value.instruction_return = Some(parse_struct_field(lexer, next.clone())?);
+                    } else {
+                        value.instruction_return = Some(parse_instruction(lexer, next.clone())?);
+                    }
                     instruction.parameters.push(value);
                 }
                 Word => {
@@ -154,7 +211,7 @@ pub fn parse_instruction(
         }
 
         if !closed {
-            return Err(ParserError::UnmatchedParenthesis(location.clone()));
+            return Err(ParserError::UnclosedOpenParen(token));
         }
     } else {
         return Err(ParserError::ReachedEOF(token));
@@ -163,6 +220,39 @@ pub fn parse_instruction(
     Ok(instruction)
 }
 
+// the fields of a struct are a name followed by a type,
+// like `(x int)`, instead of an instruction
+pub fn parse_struct_field(
+    lexer: &mut lexer_type!(),
+    token: LexerToken,
+) -> Result<LoispInstruction, ParserError> {
+    let mut field = LoispInstruction::new(token.clone());
+    field.kind = LoispInstructionType::StructField;
+
+    match lexer.next() {
+        Some(name) if name.kind == LexerTokenKind::Word => field.token = name,
+        Some(name) => return Err(ParserError::ExpectedNameToBeWord(name)),
+        None => return Err(ParserError::ReachedEOF(token)),
+    }
+
+    for next in lexer.by_ref() {
+        match next.kind {
+            LexerTokenKind::CloseParen => {
+                field.end = next.location.clone();
+                return Ok(field);
+            }
+            LexerTokenKind::Word => {
+                let mut value = LoispValue::new(next.clone());
+                value.word = Some(next.value.string);
+                field.parameters.push(value);
+            }
+            _ => return Err(ParserError::InvalidSyntax(next)),
+        }
+    }
+
+    Err(ParserError::UnclosedOpenParen(token))
+}
+
 pub fn construct_instructions_from_tokens(
     lexer: &mut lexer_type!(),
 ) -> Result<Vec<LoispInstruction>, ParserError> {
@@ -175,7 +265,7 @@ pub fn construct_instructions_from_tokens(
             OpenParen => {
                 instructions.push(parse_instruction(lexer, x)?);
             }
-            CloseParen => return Err(ParserError::UnmatchedParenthesis(x.clone())),
+            CloseParen => return Err(ParserError::UnexpectedCloseParen(x.clone())),
             Word => return Err(ParserError::InvalidSyntax(x.clone())),
             Integer => return Err(ParserError::InvalidSyntax(x.clone())),
             String => return Err(ParserError::InvalidSyntax(x.clone())),
@@ -183,3 +273,61 @@ pub fn construct_instructions_from_tokens(
     }
     Ok(instructions)
 }
+
+// the lexer stops at its first error, which is returned instead of the tokens
+pub fn try_lex(source: &str, f: String) -> Result<Vec<LexerToken>, ParserError> {
+    let mut lexer = Lexer::from_chars(source.chars(), f);
+    let tokens = lexer.by_ref().collect();
+
+    if let Some(error) = lexer.error {
+        return Err(ParserError::LexerError(error));
+    }
+
+    Ok(tokens)
+}
+
+// never exits nor panics on malformed input, every error is returned
+pub fn try_parse(source: &str, f: String) -> Result<Vec<LoispInstruction>, ParserError> {
+    let tokens = try_lex(source, f)?;
+    construct_instructions_from_tokens(&mut tokens.into_iter().peekable())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_parse_returns_an_error_for_malformed_input() {
+        let inputs = [
+            "(",
+            ")",
+            "(print 1))",
+            "(print",
+            "((print 1)",
+            "(print \"unterminated",
+            "\"",
+            "(print 'a",
+            "print",
+            "42",
+            "\"string\"",
+            "(1 2)",
+            "(\"a\")",
+            "(struct point (x 8)",
+            "(print 1) (",
+        ];
+
+        for input in inputs {
+            assert!(
+                try_parse(input, "test.loisp".to_string()).is_err(),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn try_parse_accepts_well_formed_input() {
+        let instructions = try_parse("(print 1) (print (+ 1 2))", "test.loisp".to_string());
+        assert_eq!(instructions.unwrap().len(), 2);
+    }
+}
@@ -1,33 +1,38 @@
 use super::emulator::*;
 use super::instructions::*;
 use super::ir::*;
+use super::parser::*;
 
 use std::io;
 use std::io::Write;
 use std::process::exit;
 
-pub fn evaluate_line(
-    line: String,
-    context: &mut LoispContext,
+pub fn emulate_and_print_last_value(
+    program: &IrProgram,
+    start: usize,
     emulator: &mut Emulator,
 ) -> Result<(), LoispError> {
-    if line == "" {
-        println!();
-        exit(0);
-    } else if line == "\n" {
-        return Ok(());
+    // only the instructions added since the last evaluation
+    // need their variables, memories and strings allocated
+    let mut new = IrProgram::new();
+    new.instructions = program.instructions[start..].to_vec();
+    check_jump_targets(program)?;
+    emulator.init(new)?;
+    emulator.ip = start;
+    emulate_program(program.clone(), emulator);
+    if let Some(code) = emulator.exit_code {
+        exit(code);
     }
 
-    let ir = compile_string_into_ir(line, context, "<stdin>".to_string())?;
-    emulator.init(ir.clone());
-    emulator.ip = 0;
-    emulate_program(ir.clone(), emulator);
-
     println!("===> Last value on the stack");
 
     let value;
     if let Some(v) = emulator.stack.last() {
-        value = format!("{}", v);
+        if let Some(t) = emulator.types.last() {
+            value = format!("{} ({:?})", v, t);
+        } else {
+            value = format!("{}", v);
+        }
     } else {
         value = "<none>".to_string();
     }
@@ -37,9 +42,75 @@ pub fn evaluate_line(
     Ok(())
 }
 
+pub fn evaluate_source(
+    source: String,
+    f: String,
+    context: &mut LoispContext,
+    emulator: &mut Emulator,
+    program: &mut IrProgram,
+) -> Result<(), LoispError> {
+    let instructions = try_parse(source.as_str(), f)?;
+
+    // every expression is compiled and run on its own, so the
+    // ones before an expression that fails still take effect
+    for i in instructions {
+        let start = program.instructions.len();
+
+        // an expression that fails to compile must not leave half of
+        // its definitions behind, or later lines would refer to
+        // variables and memories that the emulator never allocated
+        let previous_context = context.clone();
+        if let Err(error) = i.to_ir(program, context) {
+            program.instructions.truncate(start);
+            *context = previous_context;
+            return Err(error);
+        }
+
+        emulate_and_print_last_value(program, start, emulator)?;
+    }
+
+    Ok(())
+}
+
+pub fn evaluate_line(
+    line: String,
+    context: &mut LoispContext,
+    emulator: &mut Emulator,
+    program: &mut IrProgram,
+) -> Result<(), LoispError> {
+    if line == "" {
+        println!();
+        exit(0);
+    } else if line == "\n" {
+        return Ok(());
+    }
+
+    if line.trim() == ":clear" {
+        emulator.stack.clear();
+        emulator.types.clear();
+        emulator.ip = program.instructions.len();
+        return Ok(());
+    }
+
+    if let Some(f) = line.trim().strip_prefix(":load") {
+        if f.trim().is_empty() {
+            eprintln!("ERROR: No file was provided to `:load`");
+            return Ok(());
+        }
+        let source = read_source_file(f.trim())?;
+        return evaluate_source(source, f.trim().to_string(), context, emulator, program);
+    }
+
+    evaluate_source(line, "<stdin>".to_string(), context, emulator, program)
+}
+
 pub fn start_repl() {
     let mut context = LoispContext::new();
+    // redefining things is how code gets fixed in the REPL
+    context.allow_redefine = true;
     let mut emulator = Emulator::new();
+    let mut program = IrProgram::new();
+    emulator.track_types = true;
 
     loop {
         let mut line = String::new();
@@ -49,7 +120,7 @@ pub fn start_repl() {
 
         if line.trim() == "quit".to_string() || line.trim() == "q".to_string() {
             exit(0);
-        } else if let Err(error) = evaluate_line(line, &mut context, &mut emulator) {
+        } else if let Err(error) = evaluate_line(line, &mut context, &mut emulator, &mut program) {
             eprintln!("{}", error);
         }
     }
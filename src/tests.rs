@@ -4,6 +4,7 @@ use std::io::BufWriter;
 use std::io::Write;
 use std::path::Path;
 use std::process::Command;
+use std::process::Stdio;
 use std::str;
 
 use super::common::*;
@@ -23,6 +24,17 @@ pub struct TestCase {
     pub args: Vec<String>,
     pub stdout: String,
     pub stderr: String,
+    pub stderr_contains: Option<String>,
+    pub stdout_contains: Option<String>,
+    pub trim: bool,
+    // the flags given before the subcommand, `-s` when there are none
+    pub flags: Option<String>,
+    // the subcommand and its arguments, instead of `run` and `emulate`
+    pub cmd: Option<String>,
+    // what the command reads from stdin, instead of the stdin of the tests
+    pub stdin: Option<String>,
+    // checked only when the conf has it, since most tests exit with 0
+    pub exit_code: Option<i32>,
 }
 
 impl TestCase {
@@ -31,26 +43,68 @@ impl TestCase {
             args: vec![],
             stdout: String::new(),
             stderr: String::new(),
+            stderr_contains: None,
+            stdout_contains: None,
+            trim: true,
+            flags: None,
+            cmd: None,
+            stdin: None,
+            exit_code: None,
         }
     }
+
+    // `stderr_contains` only requires a part of stderr to match,
+    // which keeps error tests working when paths or locations change,
+    // and `stdout_contains` does the same for output that isn't the
+    // same on every run, like how long something took
+    pub fn matches(&self, got: &TestCase) -> bool {
+        let stderr_matches = match &self.stderr_contains {
+            Some(s) => got.stderr.contains(s.as_str()),
+            None => self.stderr == got.stderr,
+        };
+        let stdout_matches = match &self.stdout_contains {
+            Some(s) => got.stdout.contains(s.as_str()),
+            None => self.stdout == got.stdout,
+        };
+
+        let exit_code_matches = self.exit_code.is_none() || self.exit_code == got.exit_code;
+
+        self.args == got.args && stdout_matches && stderr_matches && exit_code_matches
+    }
 }
                                               // test      compiled
-pub fn cmd_run_return_test_case(cmd: String) -> (TestCase, bool) {
+pub fn cmd_run_return_test_case(
+    cmd: String,
+    args: &[String],
+    stdin: Option<&str>,
+    trim: bool,
+) -> (TestCase, bool) {
     print_info!("CMD", "{}", cmd);
 
     let mut compiled = true;
-    let mut test_case = TestCase {
-        args: vec![],
-        stdout: String::new(),
-        stderr: String::new(),
-    };
-
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(cmd.as_str())
-        .output()
-        .expect("Failed to run shell command");
+    let mut test_case = TestCase::new();
+    test_case.args = args.to_vec();
+    test_case.trim = trim;
+
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd.as_str());
+    let output = match stdin {
+        Some(input) => {
+            let mut child = command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .expect("Failed to run shell command");
+            // a command that exits without reading all of it closes the pipe
+            let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+            child.wait_with_output()
+        }
+        None => command.output(),
+    }
+    .expect("Failed to run shell command");
     let exit_code = output.status.code();
+    test_case.exit_code = exit_code;
 
     match exit_code {
         Some(code) => {
@@ -65,24 +119,18 @@ pub fn cmd_run_return_test_case(cmd: String) -> (TestCase, bool) {
         }
     }
 
-    test_case.stdout = String::from_utf8(output.stdout).unwrap().trim().to_string();
-    test_case.stderr = String::from_utf8(output.stderr).unwrap().trim().to_string();
-
-    for (i, s) in cmd.trim().split(' ').enumerate() {
-        if i >= 3 {
-            test_case.args.push(s.to_string());
-        }
+    test_case.stdout = String::from_utf8(output.stdout).unwrap();
+    test_case.stderr = String::from_utf8(output.stderr).unwrap();
+    if trim {
+        test_case.stdout = test_case.stdout.trim().to_string();
+        test_case.stderr = test_case.stderr.trim().to_string();
     }
 
     (test_case.clone(), compiled)
 }
 
 pub fn read_file_return_test_case(file: String) -> io::Result<TestCase> {
-    let mut test_case = TestCase {
-        args: vec![],
-        stdout: String::new(),
-        stderr: String::new(),
-    };
+    let mut test_case = TestCase::new();
 
     let source = fs::read_to_string(file.as_str())?;
 
@@ -91,21 +139,54 @@ pub fn read_file_return_test_case(file: String) -> io::Result<TestCase> {
         lines.push(s.to_string());
     }
 
+    // with `trim = false` the output is compared exactly, so only
+    // the space that follows the `=` is removed from stdout and stderr
+    for l in lines.iter() {
+        let mut tokens = l.splitn(2, '=');
+        if tokens.next().map(str::trim) == Some("trim")
+            && tokens.next().map(str::trim) == Some("false")
+        {
+            test_case.trim = false;
+        }
+    }
+
     for l in lines {
+        // only the first `=` separates the name, so that
+        // the value can contain more, like an error message
         let mut tokens: Vec<String> = vec![];
-        for t in l.trim().split('=') {
+        for t in l.trim_start().splitn(2, '=') {
             tokens.push(t.to_string());
         }
-        assert!(
-            tokens.len() == 2,
-            "Parsing Error: missing `name` or `=`. Or more than 1 `=` encountered"
-        );
+        assert!(tokens.len() == 2, "Parsing Error: missing `name` or `=`");
         let name = &tokens[0];
         let value = &tokens[1];
 
         match name.as_str().trim() {
+            "stdout" if !test_case.trim => {
+                test_case.stdout = value.strip_prefix(' ').unwrap_or(value).to_string()
+            }
+            "stderr" if !test_case.trim => {
+                test_case.stderr = value.strip_prefix(' ').unwrap_or(value).to_string()
+            }
             "stdout" => test_case.stdout = value.trim().to_string(),
             "stderr" => test_case.stderr = value.trim().to_string(),
+            "stderr_contains" => test_case.stderr_contains = Some(value.trim().to_string()),
+            "stdout_contains" => test_case.stdout_contains = Some(value.trim().to_string()),
+            "flags" => test_case.flags = Some(value.trim().to_string()),
+            "cmd" => test_case.cmd = Some(value.trim().to_string()),
+            "stdin" => test_case.stdin = Some(value.trim().to_string()),
+            "exit_code" => {
+                test_case.exit_code = Some(
+                    value
+                        .trim()
+                        .parse()
+                        .expect("Parsing Error: `exit_code` must be an integer"),
+                )
+            }
+            "trim" => assert!(
+                value.trim() == "true" || value.trim() == "false",
+                "Parsing Error: `trim` must be `true` or `false`"
+            ),
             "args" => {
                 let mut args: Vec<String> = vec![];
                 for a in value.trim().split(' ') {
@@ -158,7 +239,12 @@ pub fn save_tests_for_folder(folder: String) -> io::Result<()> {
 
     for p in paths {
         if p.ends_with(LOISP_FILE_EXTENSION) {
-            let (tc, _) = cmd_run_return_test_case(format!("./target/debug/loisp -s run {}", p));
+            let (tc, _) = cmd_run_return_test_case(
+                format!("./target/debug/loisp -s run {}", p),
+                std::slice::from_ref(&p),
+                None,
+                true,
+            );
             let tc_output = format!("{}.conf", file_name_without_extension(p));
             save_test_case_in_conf_file(tc, tc_output)?;
             println!();
@@ -200,33 +286,51 @@ pub fn run_tests_for_folder(folder: String) -> io::Result<()> {
                 }
             }
 
-            let (compilation_got, compilation_compiled) =
-                cmd_run_return_test_case(format!("./target/debug/loisp -s run {}", args));
-            let (emulation_got, emulation_compiled) =
-                cmd_run_return_test_case(format!("./target/debug/loisp -s emulate {}", args));
+            let flags = expected.flags.clone().unwrap_or("-s".to_string());
+            // a test with `cmd` only runs that subcommand, for the
+            // ones that don't behave the same with `run` and `emulate`
+            let commands = match &expected.cmd {
+                Some(cmd) => vec![format!("./target/debug/loisp {} {}", flags, cmd)],
+                None => vec![
+                    format!("./target/debug/loisp {} run {}", flags, args),
+                    format!("./target/debug/loisp {} emulate {}", flags, args),
+                ],
+            };
+            let results: Vec<(TestCase, bool)> = commands
+                .into_iter()
+                .map(|cmd| {
+                    cmd_run_return_test_case(
+                        cmd,
+                        &expected.args,
+                        expected.stdin.as_deref(),
+                        expected.trim,
+                    )
+                })
+                .collect();
+
             if !Path::new(expected_path.as_str()).exists() {
                 print_info!(
                     "WARN",
                     "No output found for `{}`, only testing if it compiles",
                     p.clone()
                 );
-                if !compilation_compiled || !emulation_compiled {
+                if results.iter().any(|(_, compiled)| !compiled) {
                     print_info!("ERROR", "Test not compiled");
                     stats.failed += 1;
                 } else {
                     stats.passed += 1;
                 }
                 stats.ignored += 1;
+            } else if let Some((got, _)) = results.iter().find(|(got, _)| !expected.matches(got)) {
+                print_info!(
+                    "ERROR",
+                    "Test failed:\n    Expected: {:#?}\n    Got: {:#?}",
+                    expected,
+                    got
+                );
+                stats.failed += 1;
             } else {
-                if expected != compilation_got {
-                    print_info!("ERROR", "Test failed:\n    Expected: {:#?}\n    Got: {:#?}", expected, compilation_got);
-                    stats.failed += 1;
-                } else if expected != emulation_got {
-                    print_info!("ERROR", "Test failed:\n    Expected: {:#?}\n    Got: {:#?}", expected, emulation_got);
-                    stats.failed += 1;
-                } else {
-                    stats.passed += 1;
-                }
+                stats.passed += 1;
             }
             println!();
         }